@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS tokens (
+  id integer PRIMARY KEY AUTOINCREMENT,
+  name text NOT NULL,
+  secret_hash text NOT NULL UNIQUE,
+  scopes text NOT NULL,
+  create_time datetime NOT NULL,
+  revoke_time datetime
+);
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS accounts (
+  id integer PRIMARY KEY AUTOINCREMENT,
+  slug text NOT NULL UNIQUE,
+  root text NOT NULL,
+  create_time datetime NOT NULL
+);
+
+ALTER TABLE images ADD COLUMN account_id integer REFERENCES accounts (id);
+ALTER TABLE tokens ADD COLUMN account_id integer REFERENCES accounts (id);
+
+CREATE INDEX IF NOT EXISTS images_account_category ON images (account_id, category);
@@ -0,0 +1,9 @@
+ALTER TABLE images ADD COLUMN width integer;
+ALTER TABLE images ADD COLUMN height integer;
+ALTER TABLE images ADD COLUMN size integer;
+ALTER TABLE images ADD COLUMN original_filename text;
+ALTER TABLE images ADD COLUMN camera text;
+ALTER TABLE images ADD COLUMN taken_at text;
+ALTER TABLE images ADD COLUMN orientation integer;
+ALTER TABLE images ADD COLUMN latitude real;
+ALTER TABLE images ADD COLUMN longitude real;
@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS blobs (
+  id integer PRIMARY KEY AUTOINCREMENT,
+  account_id integer NOT NULL REFERENCES accounts (id),
+  sha256 text NOT NULL,
+  path text NOT NULL,
+  refs integer NOT NULL,
+  UNIQUE (account_id, sha256)
+);
+
+ALTER TABLE images ADD COLUMN blob_id integer REFERENCES blobs (id);
+
+CREATE INDEX IF NOT EXISTS images_account_sha256 ON images (account_id, sha256);
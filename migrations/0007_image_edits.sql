@@ -0,0 +1,3 @@
+ALTER TABLE images ADD COLUMN title text;
+ALTER TABLE images ADD COLUMN alt text;
+ALTER TABLE images ADD COLUMN update_time datetime;
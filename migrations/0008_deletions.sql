@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS deletions (
+  id integer PRIMARY KEY AUTOINCREMENT,
+  storage text NOT NULL,
+  path text NOT NULL,
+  create_time datetime NOT NULL
+);
@@ -0,0 +1,2 @@
+ALTER TABLE images ADD COLUMN deleted_at datetime;
+CREATE INDEX IF NOT EXISTS images_deleted_at ON images (deleted_at);
@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS categories (
+  id integer PRIMARY KEY AUTOINCREMENT,
+  account_id integer NOT NULL REFERENCES accounts (id),
+  name text NOT NULL,
+  private integer NOT NULL DEFAULT 0,
+  max_upload_bytes integer,
+  allowed_variants text,
+  default_format text,
+  UNIQUE (account_id, name)
+);
+INSERT OR IGNORE INTO categories (account_id, name)
+  SELECT DISTINCT account_id, category FROM images WHERE account_id IS NOT NULL;
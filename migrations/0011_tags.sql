@@ -0,0 +1,48 @@
+CREATE TABLE IF NOT EXISTS tags (
+  id integer PRIMARY KEY AUTOINCREMENT,
+  account_id integer NOT NULL REFERENCES accounts (id),
+  name text NOT NULL,
+  UNIQUE (account_id, name)
+);
+CREATE TABLE IF NOT EXISTS image_tags (
+  image_id integer NOT NULL REFERENCES images (id),
+  tag_id integer NOT NULL REFERENCES tags (id),
+  PRIMARY KEY (image_id, tag_id)
+);
+CREATE INDEX IF NOT EXISTS image_tags_tag ON image_tags (tag_id);
+
+-- Full-text index of the searchable fields, one row per image keyed by its
+-- id and kept up to date by the triggers below
+CREATE VIRTUAL TABLE IF NOT EXISTS images_fts USING fts5 (
+  title, alt, original_filename, tags,
+  tokenize = 'unicode61 remove_diacritics 2'
+);
+INSERT INTO images_fts (rowid, title, alt, original_filename, tags)
+  SELECT id, title, alt, original_filename, '' FROM images;
+
+CREATE TRIGGER IF NOT EXISTS images_fts_insert AFTER INSERT ON images BEGIN
+  INSERT INTO images_fts (rowid, title, alt, original_filename, tags)
+    VALUES (new.id, new.title, new.alt, new.original_filename, '');
+END;
+CREATE TRIGGER IF NOT EXISTS images_fts_update
+AFTER UPDATE OF title, alt, original_filename ON images BEGIN
+  UPDATE images_fts
+    SET title = new.title, alt = new.alt, original_filename = new.original_filename
+    WHERE rowid = new.id;
+END;
+CREATE TRIGGER IF NOT EXISTS images_fts_delete AFTER DELETE ON images BEGIN
+  DELETE FROM images_fts WHERE rowid = old.id;
+  DELETE FROM image_tags WHERE image_id = old.id;
+END;
+CREATE TRIGGER IF NOT EXISTS image_tags_insert AFTER INSERT ON image_tags BEGIN
+  UPDATE images_fts SET tags = (
+    SELECT group_concat(tags.name, ' ') FROM image_tags
+    JOIN tags ON tags.id = image_tags.tag_id WHERE image_tags.image_id = new.image_id
+  ) WHERE rowid = new.image_id;
+END;
+CREATE TRIGGER IF NOT EXISTS image_tags_delete AFTER DELETE ON image_tags BEGIN
+  UPDATE images_fts SET tags = (
+    SELECT group_concat(tags.name, ' ') FROM image_tags
+    JOIN tags ON tags.id = image_tags.tag_id WHERE image_tags.image_id = old.image_id
+  ) WHERE rowid = old.image_id;
+END;
@@ -0,0 +1,24 @@
+CREATE TABLE IF NOT EXISTS albums (
+  id integer PRIMARY KEY AUTOINCREMENT,
+  uuid text NOT NULL UNIQUE,
+  account_id integer NOT NULL REFERENCES accounts (id),
+  title text NOT NULL,
+  description text,
+  cover_image_id integer REFERENCES images (id),
+  create_time datetime NOT NULL,
+  update_time datetime NOT NULL
+);
+CREATE INDEX IF NOT EXISTS albums_account ON albums (account_id);
+CREATE TABLE IF NOT EXISTS album_items (
+  album_id integer NOT NULL REFERENCES albums (id),
+  image_id integer NOT NULL REFERENCES images (id),
+  position integer NOT NULL,
+  PRIMARY KEY (album_id, image_id)
+);
+CREATE INDEX IF NOT EXISTS album_items_image ON album_items (image_id);
+
+-- Purged images leave the albums they were in
+CREATE TRIGGER IF NOT EXISTS album_items_image_delete AFTER DELETE ON images BEGIN
+  DELETE FROM album_items WHERE image_id = old.id;
+  UPDATE albums SET cover_image_id = NULL WHERE cover_image_id = old.id;
+END;
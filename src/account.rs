@@ -0,0 +1,139 @@
+use chrono::{DateTime, Utc};
+use clap::Subcommand;
+use rusqlite::Connection;
+
+use crate::{ImagioError, ImagioState};
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum AccountCommand {
+    Create { slug: String },
+    List,
+}
+
+#[derive(Debug, Clone)]
+pub struct ImagioAccount {
+    pub(crate) id: i64,
+    pub(crate) slug: String,
+    // Prefix of the account's objects in the store and cache
+    pub(crate) root: String,
+    pub(crate) create_time: DateTime<Utc>,
+}
+
+const ACCOUNT_COLUMNS: &str = "id, slug, root, create_time";
+
+impl TryFrom<&rusqlite::Row<'_>> for ImagioAccount {
+    type Error = ImagioError;
+
+    fn try_from(row: &rusqlite::Row) -> Result<Self, Self::Error> {
+        let create_time: String = row.get(3)?;
+        Ok(ImagioAccount {
+            id: row.get(0)?,
+            slug: row.get(1)?,
+            root: row.get(2)?,
+            create_time: create_time.parse()?,
+        })
+    }
+}
+
+impl ImagioAccount {
+    fn create(conn: &Connection, slug: &str, root: &str) -> Result<ImagioAccount, ImagioError> {
+        let valid = slug
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if slug.is_empty() || !valid {
+            return Err(ImagioError::ConfigError(format!(
+                "invalid account slug: {:?}",
+                slug
+            )));
+        }
+        conn.execute(
+            "INSERT INTO accounts (slug, root, create_time) VALUES (?, ?, ?)",
+            (slug, root, Utc::now().to_string()),
+        )?;
+        ImagioAccount::find(conn, slug)
+    }
+
+    pub(crate) fn find(conn: &Connection, slug: &str) -> Result<ImagioAccount, ImagioError> {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM accounts WHERE slug = ?",
+            ACCOUNT_COLUMNS
+        ))?;
+        let mut rows = stmt.query([slug])?;
+        match rows.next()? {
+            Some(row) => ImagioAccount::try_from(row),
+            None => Err(ImagioError::NotFound),
+        }
+    }
+
+    pub(crate) fn list(conn: &Connection) -> Result<Vec<ImagioAccount>, ImagioError> {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM accounts ORDER BY id",
+            ACCOUNT_COLUMNS
+        ))?;
+        let mut rows = stmt.query([])?;
+
+        let mut accounts = Vec::new();
+        while let Some(row) = rows.next()? {
+            accounts.push(ImagioAccount::try_from(row)?);
+        }
+        Ok(accounts)
+    }
+
+    // Databases from before accounts existed get the `--account-id` slug as
+    // their first account. It keeps the unprefixed storage layout and adopts
+    // all images and tokens that have no account yet.
+    pub(crate) fn adopt_legacy(conn: &Connection, slug: &str) -> Result<(), ImagioError> {
+        let account = match ImagioAccount::find(conn, slug) {
+            Ok(account) => account,
+            Err(ImagioError::NotFound) => {
+                let accounts: i64 =
+                    conn.query_row("SELECT COUNT(*) FROM accounts", [], |row| row.get(0))?;
+                if accounts > 0 {
+                    return Ok(());
+                }
+                ImagioAccount::create(conn, slug, "")?
+            }
+            Err(err) => return Err(err),
+        };
+        conn.execute(
+            "UPDATE images SET account_id = ? WHERE account_id IS NULL",
+            [account.id],
+        )?;
+        conn.execute(
+            "UPDATE tokens SET account_id = ? WHERE account_id IS NULL",
+            [account.id],
+        )?;
+        conn.execute(
+            "INSERT OR IGNORE INTO categories (account_id, name) \
+            SELECT DISTINCT account_id, category FROM images WHERE account_id = ?",
+            [account.id],
+        )?;
+        Ok(())
+    }
+}
+
+pub(crate) fn account_command(
+    conn: &Connection,
+    command: AccountCommand,
+) -> Result<(), ImagioError> {
+    match command {
+        AccountCommand::Create { slug } => {
+            let account = ImagioAccount::create(conn, &slug, &slug)?;
+            tracing::info!("Created account {}", account.slug);
+        }
+        AccountCommand::List => {
+            for account in ImagioAccount::list(conn)? {
+                println!("{}\t{}", account.slug, account.create_time);
+            }
+        }
+    }
+    Ok(())
+}
+
+impl ImagioState {
+    pub(crate) async fn account(&self, slug: &str) -> Result<ImagioAccount, ImagioError> {
+        let lock = self.db.read().await;
+        let conn = &lock.lock().await;
+        ImagioAccount::find(conn, slug)
+    }
+}
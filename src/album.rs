@@ -0,0 +1,500 @@
+use chrono::Utc;
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::{account::ImagioAccount, app::SELECT_IMAGES, ImagioError, ImagioImage, ImagioState};
+
+// Without an explicit cover, the first image of the album stands in.
+// Images in the trash are left out, but stay in the album until purged.
+const SELECT_ALBUMS: &str = "SELECT albums.id, albums.uuid, albums.title, albums.description, \
+    COALESCE(covers.uuid, (SELECT images.uuid FROM album_items \
+    JOIN images ON images.id = album_items.image_id \
+    WHERE album_items.album_id = albums.id AND images.deleted_at IS NULL \
+    ORDER BY album_items.position LIMIT 1)), \
+    (SELECT COUNT(*) FROM album_items JOIN images ON images.id = album_items.image_id \
+    WHERE album_items.album_id = albums.id AND images.deleted_at IS NULL) \
+    FROM albums LEFT JOIN images AS covers \
+    ON covers.id = albums.cover_image_id AND covers.deleted_at IS NULL";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImagioAlbum {
+    #[serde(skip)]
+    id: i64,
+    uuid: String,
+    title: String,
+    description: Option<String>,
+    cover: Option<String>,
+    images: u64,
+}
+
+impl TryFrom<&rusqlite::Row<'_>> for ImagioAlbum {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &rusqlite::Row<'_>) -> Result<Self, Self::Error> {
+        Ok(ImagioAlbum {
+            id: row.get(0)?,
+            uuid: row.get(1)?,
+            title: row.get(2)?,
+            description: row.get(3)?,
+            cover: row.get(4)?,
+            images: row.get(5)?,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AlbumDetail {
+    #[serde(flatten)]
+    album: ImagioAlbum,
+    items: Vec<ImagioImage>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NewAlbum {
+    title: String,
+    description: Option<String>,
+}
+
+// Changes to an album. `cover` must be one of its images; `null` clears it.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlbumPatch {
+    title: Option<String>,
+    #[serde(default, with = "serde_with::rust::double_option")]
+    description: Option<Option<String>>,
+    #[serde(default, with = "serde_with::rust::double_option")]
+    cover: Option<Option<String>>,
+}
+
+// Images to add to an album, inserted together at a position (counted among
+// the images shown) or at the end. Images already in it stay where they are.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlbumItems {
+    pub(crate) images: Vec<String>,
+    position: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlbumMove {
+    pub(crate) position: usize,
+}
+
+fn validate_title(title: &str) -> Result<(), ImagioError> {
+    if title.trim().is_empty() {
+        return Err(ImagioError::InvalidInput("empty album title".to_string()));
+    }
+    Ok(())
+}
+
+fn find_album(
+    conn: &Connection,
+    account: &ImagioAccount,
+    uuid: &str,
+) -> Result<ImagioAlbum, ImagioError> {
+    conn.query_row(
+        &format!(
+            "{} WHERE albums.account_id = ? AND albums.uuid = ?",
+            SELECT_ALBUMS
+        ),
+        (account.id, uuid),
+        |row| ImagioAlbum::try_from(row),
+    )
+    .optional()?
+    .ok_or(ImagioError::NotFound)
+}
+
+// Ids of the given images, which must be in the account and not in the trash
+fn image_ids(
+    conn: &Connection,
+    account: &ImagioAccount,
+    uuids: &[String],
+) -> Result<Vec<i64>, ImagioError> {
+    let mut ids = Vec::new();
+    for uuid in uuids {
+        let id = conn
+            .query_row(
+                "SELECT id FROM images WHERE account_id = ? AND uuid = ? AND deleted_at IS NULL",
+                (account.id, uuid),
+                |row| row.get::<_, i64>(0),
+            )
+            .optional()?
+            .ok_or_else(|| ImagioError::InvalidInput(format!("unknown image: {}", uuid)))?;
+        if !ids.contains(&id) {
+            ids.push(id);
+        }
+    }
+    Ok(ids)
+}
+
+// Image ids of an album in order, and whether each is shown (not in the trash)
+fn album_items(conn: &Connection, album_id: i64) -> Result<Vec<(i64, bool)>, ImagioError> {
+    let mut stmt = conn.prepare(
+        "SELECT album_items.image_id, images.deleted_at IS NULL FROM album_items \
+        JOIN images ON images.id = album_items.image_id \
+        WHERE album_items.album_id = ? ORDER BY album_items.position",
+    )?;
+    let rows = stmt.query_map([album_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    Ok(rows.collect::<Result<Vec<_>, _>>()?)
+}
+
+// Number the items from 0 in the given order.
+fn save_items(conn: &Connection, album_id: i64, items: &[(i64, bool)]) -> Result<(), ImagioError> {
+    conn.execute("DELETE FROM album_items WHERE album_id = ?", [album_id])?;
+    for (position, (image_id, _)) in items.iter().enumerate() {
+        conn.execute(
+            "INSERT INTO album_items (album_id, image_id, position) VALUES (?, ?, ?)",
+            (album_id, image_id, position as i64),
+        )?;
+    }
+    conn.execute(
+        "UPDATE albums SET update_time = ? WHERE id = ?",
+        (Utc::now().to_string(), album_id),
+    )?;
+    Ok(())
+}
+
+// Index in all items before the shown item at `position`, or the end.
+fn index_of_shown(items: &[(i64, bool)], position: Option<usize>) -> usize {
+    position
+        .and_then(|position| {
+            items
+                .iter()
+                .enumerate()
+                .filter(|(_, (_, shown))| *shown)
+                .nth(position)
+                .map(|(index, _)| index)
+        })
+        .unwrap_or(items.len())
+}
+
+impl ImagioState {
+    pub(crate) async fn list_albums(
+        &self,
+        account: &ImagioAccount,
+    ) -> Result<Vec<ImagioAlbum>, ImagioError> {
+        let lock = self.db.read().await;
+        let conn = &lock.lock().await;
+        let mut stmt = conn.prepare(&format!(
+            "{} WHERE albums.account_id = ? ORDER BY albums.id",
+            SELECT_ALBUMS
+        ))?;
+        let rows = stmt.query_map([account.id], |row| ImagioAlbum::try_from(row))?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    // An album with its images in order and their variant URLs.
+    pub(crate) async fn get_album(
+        &self,
+        account: &ImagioAccount,
+        uuid: &str,
+    ) -> Result<AlbumDetail, ImagioError> {
+        let (album, mut items) = {
+            let lock = self.db.read().await;
+            let conn = &lock.lock().await;
+            let album = find_album(conn, account, uuid)?;
+            let mut stmt = conn.prepare(&format!(
+                "{} JOIN album_items ON album_items.image_id = images.id \
+                WHERE album_items.album_id = ? AND images.deleted_at IS NULL \
+                ORDER BY album_items.position",
+                SELECT_IMAGES
+            ))?;
+            let rows = stmt.query_map([album.id], |row| Ok(ImagioImage::try_from(row)))?;
+            let images = rows.collect::<Result<Result<Vec<_>, _>, _>>()??;
+            (album, images)
+        };
+        self.link(&mut items).await?;
+        Ok(AlbumDetail { album, items })
+    }
+
+    pub(crate) async fn create_album(
+        &self,
+        account: &ImagioAccount,
+        new: NewAlbum,
+    ) -> Result<ImagioAlbum, ImagioError> {
+        validate_title(&new.title)?;
+        let uuid = uuid::Uuid::new_v4().to_string();
+        let now = Utc::now().to_string();
+        let lock = self.db.write().await;
+        let conn = &lock.lock().await;
+        conn.execute(
+            "INSERT INTO albums (uuid, account_id, title, description, create_time, update_time) \
+            VALUES (?, ?, ?, ?, ?, ?)",
+            (&uuid, account.id, &new.title, &new.description, &now, &now),
+        )?;
+        tracing::info!("Album {} created", uuid);
+        find_album(conn, account, &uuid)
+    }
+
+    pub(crate) async fn update_album(
+        &self,
+        account: &ImagioAccount,
+        uuid: &str,
+        patch: AlbumPatch,
+    ) -> Result<ImagioAlbum, ImagioError> {
+        let lock = self.db.write().await;
+        let conn = &mut lock.lock().await;
+        let tx = conn.transaction()?;
+        let album = find_album(&tx, account, uuid)?;
+        if let Some(title) = &patch.title {
+            validate_title(title)?;
+            tx.execute(
+                "UPDATE albums SET title = ? WHERE id = ?",
+                (title, album.id),
+            )?;
+        }
+        if let Some(description) = &patch.description {
+            tx.execute(
+                "UPDATE albums SET description = ? WHERE id = ?",
+                (description, album.id),
+            )?;
+        }
+        if let Some(cover) = &patch.cover {
+            let cover_id = match cover {
+                Some(cover) => {
+                    let id = image_ids(&tx, account, std::slice::from_ref(cover))?[0];
+                    if !album_items(&tx, album.id)?
+                        .iter()
+                        .any(|(item, _)| *item == id)
+                    {
+                        return Err(ImagioError::InvalidInput(format!(
+                            "cover is not in the album: {}",
+                            cover
+                        )));
+                    }
+                    Some(id)
+                }
+                None => None,
+            };
+            tx.execute(
+                "UPDATE albums SET cover_image_id = ? WHERE id = ?",
+                (cover_id, album.id),
+            )?;
+        }
+        tx.execute(
+            "UPDATE albums SET update_time = ? WHERE id = ?",
+            (Utc::now().to_string(), album.id),
+        )?;
+        let album = find_album(&tx, account, uuid)?;
+        tx.commit()?;
+        Ok(album)
+    }
+
+    // Delete an album, leaving its images alone.
+    pub(crate) async fn delete_album(
+        &self,
+        account: &ImagioAccount,
+        uuid: &str,
+    ) -> Result<(), ImagioError> {
+        let lock = self.db.write().await;
+        let conn = &mut lock.lock().await;
+        let tx = conn.transaction()?;
+        let album = find_album(&tx, account, uuid)?;
+        tx.execute("DELETE FROM album_items WHERE album_id = ?", [album.id])?;
+        tx.execute("DELETE FROM albums WHERE id = ?", [album.id])?;
+        tx.commit()?;
+        tracing::info!("Album {} deleted", uuid);
+        Ok(())
+    }
+
+    pub(crate) async fn add_album_items(
+        &self,
+        account: &ImagioAccount,
+        uuid: &str,
+        add: AlbumItems,
+    ) -> Result<(), ImagioError> {
+        let lock = self.db.write().await;
+        let conn = &mut lock.lock().await;
+        let tx = conn.transaction()?;
+        let album = find_album(&tx, account, uuid)?;
+        let mut items = album_items(&tx, album.id)?;
+        let added = image_ids(&tx, account, &add.images)?
+            .into_iter()
+            .filter(|id| !items.iter().any(|(item, _)| item == id))
+            .map(|id| (id, true))
+            .collect::<Vec<_>>();
+        let index = index_of_shown(&items, add.position);
+        items.splice(index..index, added);
+        save_items(&tx, album.id, &items)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    // Replace the images of an album with the given ones, in that order.
+    // Images in the trash cannot be given; they stay in the album, after as
+    // many shown images as before.
+    pub(crate) async fn set_album_items(
+        &self,
+        account: &ImagioAccount,
+        uuid: &str,
+        images: &[String],
+    ) -> Result<(), ImagioError> {
+        let lock = self.db.write().await;
+        let conn = &mut lock.lock().await;
+        let tx = conn.transaction()?;
+        let album = find_album(&tx, account, uuid)?;
+        let mut items = image_ids(&tx, account, images)?
+            .into_iter()
+            .map(|id| (id, true))
+            .collect::<Vec<_>>();
+        let mut shown_before = 0;
+        for (id, shown) in album_items(&tx, album.id)? {
+            if shown {
+                shown_before += 1;
+            } else {
+                let index = index_of_shown(&items, Some(shown_before));
+                items.insert(index, (id, false));
+            }
+        }
+        save_items(&tx, album.id, &items)?;
+        tx.execute(
+            "UPDATE albums SET cover_image_id = NULL WHERE id = ? AND cover_image_id NOT IN \
+            (SELECT image_id FROM album_items WHERE album_id = ?)",
+            (album.id, album.id),
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    // Move an image of an album to another position among the images shown.
+    pub(crate) async fn move_album_item(
+        &self,
+        account: &ImagioAccount,
+        uuid: &str,
+        image: &str,
+        position: usize,
+    ) -> Result<(), ImagioError> {
+        let lock = self.db.write().await;
+        let conn = &mut lock.lock().await;
+        let tx = conn.transaction()?;
+        let album = find_album(&tx, account, uuid)?;
+        let mut items = album_items(&tx, album.id)?;
+        let image_id = match image_ids(&tx, account, &[image.to_string()]) {
+            Ok(ids) => ids[0],
+            Err(ImagioError::InvalidInput(_)) => return Err(ImagioError::NotFound),
+            Err(err) => return Err(err),
+        };
+        let index = items
+            .iter()
+            .position(|(item, _)| *item == image_id)
+            .ok_or(ImagioError::NotFound)?;
+        let item = items.remove(index);
+        let index = index_of_shown(&items, Some(position));
+        items.insert(index, item);
+        save_items(&tx, album.id, &items)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub(crate) async fn remove_album_item(
+        &self,
+        account: &ImagioAccount,
+        uuid: &str,
+        image: &str,
+    ) -> Result<(), ImagioError> {
+        let lock = self.db.write().await;
+        let conn = &mut lock.lock().await;
+        let tx = conn.transaction()?;
+        let album = find_album(&tx, account, uuid)?;
+        let removed = tx.execute(
+            "DELETE FROM album_items WHERE album_id = ? AND image_id = \
+            (SELECT id FROM images WHERE account_id = ? AND uuid = ?)",
+            (album.id, account.id, image),
+        )?;
+        if removed == 0 {
+            return Err(ImagioError::NotFound);
+        }
+        let items = album_items(&tx, album.id)?;
+        save_items(&tx, album.id, &items)?;
+        tx.execute(
+            "UPDATE albums SET cover_image_id = NULL WHERE id = ? AND cover_image_id NOT IN \
+            (SELECT image_id FROM album_items WHERE album_id = ?)",
+            (album.id, album.id),
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    fn setup() -> (Connection, i64, Vec<i64>) {
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::migrate(&mut conn).unwrap();
+        let now = Utc::now().to_string();
+        conn.execute(
+            "INSERT INTO accounts (slug, root, create_time) VALUES ('test', '', ?)",
+            [&now],
+        )
+        .unwrap();
+        let account_id = conn.last_insert_rowid();
+        conn.execute(
+            "INSERT INTO albums (uuid, account_id, title, create_time, update_time) \
+            VALUES ('album', ?, 'Album', ?, ?)",
+            (account_id, &now, &now),
+        )
+        .unwrap();
+        let album_id = conn.last_insert_rowid();
+        let images = (0..3)
+            .map(|i| {
+                conn.execute(
+                    "INSERT INTO images (uuid, category, mime, create_time, account_id) \
+                    VALUES (?, 'photo', 'image/png', ?, ?)",
+                    (format!("image-{}", i), &now, account_id),
+                )
+                .unwrap();
+                conn.last_insert_rowid()
+            })
+            .collect();
+        (conn, album_id, images)
+    }
+
+    fn positions(conn: &Connection, album_id: i64) -> Vec<(i64, i64)> {
+        let mut stmt = conn
+            .prepare(
+                "SELECT image_id, position FROM album_items WHERE album_id = ? \
+                ORDER BY position",
+            )
+            .unwrap();
+        let rows = stmt
+            .query_map([album_id], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap();
+        rows.collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn finds_index_of_shown_items() {
+        // The second item is in the trash
+        let items = [(1, true), (2, false), (3, true), (4, true)];
+        assert_eq!(index_of_shown(&items, Some(0)), 0);
+        assert_eq!(index_of_shown(&items, Some(1)), 2);
+        assert_eq!(index_of_shown(&items, Some(2)), 3);
+        // Past the shown items, or without a position, is the end
+        assert_eq!(index_of_shown(&items, Some(3)), 4);
+        assert_eq!(index_of_shown(&items, Some(100)), 4);
+        assert_eq!(index_of_shown(&items, None), 4);
+        assert_eq!(index_of_shown(&[], Some(0)), 0);
+    }
+
+    #[test]
+    fn saves_items_in_order() {
+        let (conn, album_id, images) = setup();
+        let (a, b, c) = (images[0], images[1], images[2]);
+
+        save_items(&conn, album_id, &[(c, true), (a, true), (b, false)]).unwrap();
+        assert_eq!(positions(&conn, album_id), vec![(c, 0), (a, 1), (b, 2)]);
+        assert_eq!(
+            album_items(&conn, album_id).unwrap(),
+            vec![(c, true), (a, true), (b, true)]
+        );
+
+        // Removing an item renumbers the rest from 0
+        save_items(&conn, album_id, &[(a, true), (b, true)]).unwrap();
+        assert_eq!(positions(&conn, album_id), vec![(a, 0), (b, 1)]);
+    }
+}
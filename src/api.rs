@@ -1,81 +1,508 @@
 use std::sync::Arc;
 
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    account::ImagioAccount,
+    album::{AlbumDetail, AlbumItems, AlbumMove, AlbumPatch, ImagioAlbum, NewAlbum},
+    auth::authorize,
+    category::{CategoryPatch, CategoryUsage, NewCategory},
+    edit::{validate_category, ImagePatch},
+    extract::{Json, Multipart, Path, Query},
+    query::{ImagePage, ImageQuery},
+    tag::TagList,
+    ImagioError, ImagioImage, ImagioState,
+};
 use axum::{
-    extract::{DefaultBodyLimit, Multipart, Path, State},
+    extract::{DefaultBodyLimit, State},
+    http::StatusCode,
+    middleware,
     response::Result,
-    routing::{delete, get, put},
-    Json, Router,
+    routing::{delete, get, patch, post, put},
+    Extension, Router,
 };
-use image::io::Reader as ImageReader;
-
-use crate::{variant::Variant, ImagioError, ImagioImage, ImagioState};
 
 async fn list_images_handler(
     State(state): State<Arc<ImagioState>>,
-    Path((category, limit, skip)): Path<(String, usize, usize)>,
+    Extension(account): Extension<ImagioAccount>,
+    Path((_, category, limit, skip)): Path<(String, String, usize, usize)>,
 ) -> Result<Json<Vec<ImagioImage>>, ImagioError> {
     tracing::info!("Requesting list of images");
-    let images = state.list(category, limit, skip).await?;
+    let mut images = state.list(&account, category, limit, skip).await?;
+    state.link(&mut images).await?;
     Ok(Json(images))
 }
 
+async fn query_images_handler(
+    State(state): State<Arc<ImagioState>>,
+    Extension(account): Extension<ImagioAccount>,
+    Query(params): Query<Vec<(String, String)>>,
+) -> Result<Json<ImagePage>, ImagioError> {
+    let mut page = state.query(&account, ImageQuery::try_from(params)?).await?;
+    state.link(&mut page.images).await?;
+    Ok(Json(page))
+}
+
+// Like listing, with the `q` full-text search mandatory
+async fn search_images_handler(
+    State(state): State<Arc<ImagioState>>,
+    Extension(account): Extension<ImagioAccount>,
+    Query(params): Query<Vec<(String, String)>>,
+) -> Result<Json<ImagePage>, ImagioError> {
+    let query = ImageQuery::try_from(params)?;
+    if !query.has_search() {
+        return Err(ImagioError::InvalidInput("missing or blank q".to_string()));
+    }
+    let mut page = state.query(&account, query).await?;
+    state.link(&mut page.images).await?;
+    Ok(Json(page))
+}
+
 async fn get_image_handler(
     State(state): State<Arc<ImagioState>>,
-    Path(uuid): Path<String>,
+    Extension(account): Extension<ImagioAccount>,
+    Path((_, uuid)): Path<(String, String)>,
 ) -> Result<Json<ImagioImage>, ImagioError> {
     tracing::info!("Requesting image with uuid: {}", uuid);
-    let image = state.get(&uuid).await?;
-    Ok(Json(image))
+    let image = state.get(&account, &uuid).await?;
+    Ok(Json(state.linked(image).await?))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum UploadResult {
+    Ok(Box<ImagioImage>),
+    Error {
+        filename: Option<String>,
+        code: &'static str,
+        message: String,
+        #[serde(skip)]
+        status: StatusCode,
+    },
+}
+
+impl UploadResult {
+    fn error(filename: Option<String>, err: ImagioError) -> Self {
+        let (status, code) = err.status();
+        if status.is_server_error() {
+            tracing::error!("Upload of {:?} failed: {:?}", filename, err);
+        } else {
+            tracing::debug!("Upload of {:?} failed: {:?}", filename, err);
+        }
+        UploadResult::Error {
+            filename,
+            code,
+            message: err.message(),
+            status,
+        }
+    }
+}
+
+// `200` when every file was stored, the status of the first failure when
+// none was, and `207` for a mix of both.
+fn upload_status(results: &[UploadResult]) -> StatusCode {
+    let mut failures = results.iter().filter_map(|result| match result {
+        UploadResult::Error { status, .. } => Some(*status),
+        UploadResult::Ok(_) => None,
+    });
+    match failures.next() {
+        None => StatusCode::OK,
+        Some(status) if failures.count() + 1 == results.len() => status,
+        Some(_) => StatusCode::MULTI_STATUS,
+    }
 }
 
 async fn put_image_handler(
     State(state): State<Arc<ImagioState>>,
-    Path(category): Path<String>,
+    Extension(account): Extension<ImagioAccount>,
+    Path((_, category)): Path<(String, String)>,
+    mut payload: Multipart,
+) -> Result<(StatusCode, Json<Vec<UploadResult>>), ImagioError> {
+    validate_category(&category)?;
+    // Stream each file to the store, reporting on every one of them
+    let mut results = Vec::new();
+    loop {
+        let field = match payload.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            // Nothing was read from a request that is broken from the start
+            Err(err) if results.is_empty() => return Err(err.into()),
+            Err(err) => {
+                results.push(UploadResult::error(None, err.into()));
+                break;
+            }
+        };
+        let filename = field.file_name().map(str::to_string);
+        if results.len() >= state.max_upload_files {
+            let err = ImagioError::TooManyFiles(state.max_upload_files);
+            results.push(UploadResult::error(filename, err));
+            continue;
+        }
+        let uploaded = async {
+            let image = state
+                .upload(&account, &category, filename.clone(), field)
+                .await?;
+            state.linked(image).await
+        };
+        match uploaded.await {
+            Ok(image) => {
+                tracing::info!("New image uploaded with uuid: {}", image.uuid);
+                results.push(UploadResult::Ok(Box::new(image)));
+            }
+            Err(err) => results.push(UploadResult::error(filename, err)),
+        }
+    }
+    if results.is_empty() {
+        return Err(ImagioError::InvalidInput("missing file".to_string()));
+    }
+    Ok((upload_status(&results), Json(results)))
+}
+
+async fn patch_image_handler(
+    State(state): State<Arc<ImagioState>>,
+    Extension(account): Extension<ImagioAccount>,
+    Path((_, uuid)): Path<(String, String)>,
+    Json(patch): Json<ImagePatch>,
+) -> Result<Json<ImagioImage>, ImagioError> {
+    let image = state.update(&account, &uuid, patch).await?;
+    tracing::info!("Image updated with uuid: {}", image.uuid);
+    Ok(Json(state.linked(image).await?))
+}
+
+async fn replace_image_handler(
+    State(state): State<Arc<ImagioState>>,
+    Extension(account): Extension<ImagioAccount>,
+    Path((_, uuid)): Path<(String, String)>,
     mut payload: Multipart,
 ) -> Result<Json<ImagioImage>, ImagioError> {
-    let uuid = uuid::Uuid::new_v4().to_string();
-
-    // Upload the image to local
-    if let Ok(Some(field)) = payload.next_field().await {
-        let data = field.bytes().await?.to_vec();
-        let image_blob =
-            ImageReader::new(std::io::Cursor::new(data.clone())).with_guessed_format()?;
-        let mime_str = image_blob.format().unwrap().to_mime_type();
-        let image = ImagioImage::new(&uuid, &category, mime_str)?;
-
-        // Write the image to the store
-        image
-            .store(
-                data,
-                state.storage.store.clone(),
-                &image.filename(&Variant::Original),
-            )
-            .await?;
-
-        // Save the image to the database
-        state.put(&image).await?;
-        tracing::info!("New image uploaded with uuid: {}", uuid);
-        return Ok(Json(image));
+    let field = payload
+        .next_field()
+        .await?
+        .ok_or_else(|| ImagioError::InvalidInput("missing file".to_string()))?;
+    let filename = field.file_name().map(str::to_string);
+    let image = state.replace(&account, &uuid, filename, field).await?;
+    Ok(Json(state.linked(image).await?))
+}
+
+#[derive(Debug, Deserialize)]
+struct FetchRequest {
+    url: String,
+}
+
+async fn fetch_image_handler(
+    State(state): State<Arc<ImagioState>>,
+    Extension(account): Extension<ImagioAccount>,
+    Path((_, category)): Path<(String, String)>,
+    Json(request): Json<FetchRequest>,
+) -> Result<Json<ImagioImage>, ImagioError> {
+    validate_category(&category)?;
+    tracing::info!("Fetching image from: {}", request.url);
+    let (filename, body) = state.fetcher.fetch(&request.url).await?;
+    let image = state.upload(&account, &category, filename, body).await?;
+    tracing::info!("New image fetched with uuid: {}", image.uuid);
+    Ok(Json(state.linked(image).await?))
+}
+
+#[derive(Debug, Deserialize)]
+struct SignParams {
+    #[serde(default = "default_sign_variant")]
+    variant: String,
+    ttl: Option<u32>,
+}
+
+fn default_sign_variant() -> String {
+    "original".to_string()
+}
+
+const DEFAULT_SIGN_TTL: u32 = 3600;
+
+#[derive(Debug, Serialize)]
+struct SignedUrl {
+    url: String,
+    expires: i64,
+}
+
+async fn sign_image_handler(
+    State(state): State<Arc<ImagioState>>,
+    Extension(account): Extension<ImagioAccount>,
+    Path((_, uuid)): Path<(String, String)>,
+    Query(params): Query<SignParams>,
+) -> Result<Json<SignedUrl>, ImagioError> {
+    let signer = state.signer.as_ref().ok_or(ImagioError::SigningDisabled)?;
+    let ttl = params
+        .ttl
+        .unwrap_or(DEFAULT_SIGN_TTL.min(state.max_sign_ttl));
+    if !(1..=state.max_sign_ttl).contains(&ttl) {
+        return Err(ImagioError::InvalidInput(format!(
+            "ttl must be between 1 and {} seconds",
+            state.max_sign_ttl
+        )));
     }
+    state.variants.resolve(&params.variant)?;
+    let image = state.get(&account, &uuid).await?;
 
-    Err(ImagioError::NotFound)
+    let (path, expires) = signer.signed_path(&image.uuid, &params.variant, ttl);
+    let url = format!("{}{}", state.public_url, path);
+    Ok(Json(SignedUrl { url, expires }))
 }
 
-async fn delete_image_handler(State(state): State<Arc<ImagioState>>, Path(uuid): Path<String>) {
-    state.delete(&uuid).await.ok();
+async fn delete_image_handler(
+    State(state): State<Arc<ImagioState>>,
+    Extension(account): Extension<ImagioAccount>,
+    Path((_, uuid)): Path<(String, String)>,
+) -> Result<StatusCode, ImagioError> {
+    state.delete(&account, &uuid).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_trash_handler(
+    State(state): State<Arc<ImagioState>>,
+    Extension(account): Extension<ImagioAccount>,
+    Path((_, limit, skip)): Path<(String, usize, usize)>,
+) -> Result<Json<Vec<ImagioImage>>, ImagioError> {
+    let images = state.list_trash(&account, limit, skip).await?;
+    Ok(Json(images))
+}
+
+async fn restore_image_handler(
+    State(state): State<Arc<ImagioState>>,
+    Extension(account): Extension<ImagioAccount>,
+    Path((_, uuid)): Path<(String, String)>,
+) -> Result<Json<ImagioImage>, ImagioError> {
+    let image = state.restore(&account, &uuid).await?;
+    Ok(Json(state.linked(image).await?))
+}
+
+async fn list_categories_handler(
+    State(state): State<Arc<ImagioState>>,
+    Extension(account): Extension<ImagioAccount>,
+) -> Result<Json<Vec<CategoryUsage>>, ImagioError> {
+    let categories = state.list_categories(&account).await?;
+    Ok(Json(categories))
+}
+
+async fn get_category_handler(
+    State(state): State<Arc<ImagioState>>,
+    Extension(account): Extension<ImagioAccount>,
+    Path((_, name)): Path<(String, String)>,
+) -> Result<Json<CategoryUsage>, ImagioError> {
+    let category = state.get_category(&account, &name).await?;
+    Ok(Json(category))
+}
+
+async fn create_category_handler(
+    State(state): State<Arc<ImagioState>>,
+    Extension(account): Extension<ImagioAccount>,
+    Json(new): Json<NewCategory>,
+) -> Result<(StatusCode, Json<CategoryUsage>), ImagioError> {
+    let category = state.create_category(&account, new).await?;
+    Ok((StatusCode::CREATED, Json(category)))
+}
+
+async fn patch_category_handler(
+    State(state): State<Arc<ImagioState>>,
+    Extension(account): Extension<ImagioAccount>,
+    Path((_, name)): Path<(String, String)>,
+    Json(patch): Json<CategoryPatch>,
+) -> Result<Json<CategoryUsage>, ImagioError> {
+    let category = state.update_category(&account, &name, patch).await?;
+    Ok(Json(category))
+}
+
+async fn delete_category_handler(
+    State(state): State<Arc<ImagioState>>,
+    Extension(account): Extension<ImagioAccount>,
+    Path((_, name)): Path<(String, String)>,
+) -> Result<StatusCode, ImagioError> {
+    state.delete_category(&account, &name).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_tags_handler(
+    State(state): State<Arc<ImagioState>>,
+    Extension(account): Extension<ImagioAccount>,
+    Path((_, uuid)): Path<(String, String)>,
+) -> Result<Json<Vec<String>>, ImagioError> {
+    let image = state.get(&account, &uuid).await?;
+    Ok(Json(image.tags))
+}
+
+async fn add_tags_handler(
+    State(state): State<Arc<ImagioState>>,
+    Extension(account): Extension<ImagioAccount>,
+    Path((_, uuid)): Path<(String, String)>,
+    Json(list): Json<TagList>,
+) -> Result<Json<Vec<String>>, ImagioError> {
+    let image = state.tag(&account, &uuid, &list.tags).await?;
+    Ok(Json(image.tags))
+}
+
+async fn set_tags_handler(
+    State(state): State<Arc<ImagioState>>,
+    Extension(account): Extension<ImagioAccount>,
+    Path((_, uuid)): Path<(String, String)>,
+    Json(list): Json<TagList>,
+) -> Result<Json<Vec<String>>, ImagioError> {
+    let image = state.set_tags(&account, &uuid, &list.tags).await?;
+    Ok(Json(image.tags))
+}
+
+async fn delete_tag_handler(
+    State(state): State<Arc<ImagioState>>,
+    Extension(account): Extension<ImagioAccount>,
+    Path((_, uuid, tag)): Path<(String, String, String)>,
+) -> Result<StatusCode, ImagioError> {
+    state.untag(&account, &uuid, &tag).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_albums_handler(
+    State(state): State<Arc<ImagioState>>,
+    Extension(account): Extension<ImagioAccount>,
+) -> Result<Json<Vec<ImagioAlbum>>, ImagioError> {
+    let albums = state.list_albums(&account).await?;
+    Ok(Json(albums))
+}
+
+async fn create_album_handler(
+    State(state): State<Arc<ImagioState>>,
+    Extension(account): Extension<ImagioAccount>,
+    Json(new): Json<NewAlbum>,
+) -> Result<(StatusCode, Json<ImagioAlbum>), ImagioError> {
+    let album = state.create_album(&account, new).await?;
+    Ok((StatusCode::CREATED, Json(album)))
+}
+
+async fn get_album_handler(
+    State(state): State<Arc<ImagioState>>,
+    Extension(account): Extension<ImagioAccount>,
+    Path((_, uuid)): Path<(String, String)>,
+) -> Result<Json<AlbumDetail>, ImagioError> {
+    let album = state.get_album(&account, &uuid).await?;
+    Ok(Json(album))
+}
+
+async fn patch_album_handler(
+    State(state): State<Arc<ImagioState>>,
+    Extension(account): Extension<ImagioAccount>,
+    Path((_, uuid)): Path<(String, String)>,
+    Json(patch): Json<AlbumPatch>,
+) -> Result<Json<ImagioAlbum>, ImagioError> {
+    let album = state.update_album(&account, &uuid, patch).await?;
+    Ok(Json(album))
+}
+
+async fn delete_album_handler(
+    State(state): State<Arc<ImagioState>>,
+    Extension(account): Extension<ImagioAccount>,
+    Path((_, uuid)): Path<(String, String)>,
+) -> Result<StatusCode, ImagioError> {
+    state.delete_album(&account, &uuid).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn add_album_items_handler(
+    State(state): State<Arc<ImagioState>>,
+    Extension(account): Extension<ImagioAccount>,
+    Path((_, uuid)): Path<(String, String)>,
+    Json(add): Json<AlbumItems>,
+) -> Result<Json<AlbumDetail>, ImagioError> {
+    state.add_album_items(&account, &uuid, add).await?;
+    Ok(Json(state.get_album(&account, &uuid).await?))
+}
+
+async fn set_album_items_handler(
+    State(state): State<Arc<ImagioState>>,
+    Extension(account): Extension<ImagioAccount>,
+    Path((_, uuid)): Path<(String, String)>,
+    Json(set): Json<AlbumItems>,
+) -> Result<Json<AlbumDetail>, ImagioError> {
+    state.set_album_items(&account, &uuid, &set.images).await?;
+    Ok(Json(state.get_album(&account, &uuid).await?))
+}
+
+async fn move_album_item_handler(
+    State(state): State<Arc<ImagioState>>,
+    Extension(account): Extension<ImagioAccount>,
+    Path((_, uuid, image)): Path<(String, String, String)>,
+    Json(to): Json<AlbumMove>,
+) -> Result<Json<AlbumDetail>, ImagioError> {
+    state
+        .move_album_item(&account, &uuid, &image, to.position)
+        .await?;
+    Ok(Json(state.get_album(&account, &uuid).await?))
+}
+
+async fn remove_album_item_handler(
+    State(state): State<Arc<ImagioState>>,
+    Extension(account): Extension<ImagioAccount>,
+    Path((_, uuid, image)): Path<(String, String, String)>,
+) -> Result<StatusCode, ImagioError> {
+    state.remove_album_item(&account, &uuid, &image).await?;
+    Ok(StatusCode::NO_CONTENT)
 }
 
 pub fn api_router(state: Arc<ImagioState>) -> Router<Arc<ImagioState>> {
+    // Room for every file at its maximum size plus the multipart framing
+    let body_limit = state
+        .limits
+        .max_bytes
+        .saturating_mul(state.max_upload_files as u64)
+        .saturating_add(64 * 1024);
+    let body_limit = usize::try_from(body_limit).unwrap_or(usize::MAX);
+
     Router::new()
         // List images
         .route("/images/:category/:limit/:skip", get(list_images_handler))
+        // List images with filters, sorting and cursor pagination
+        .route("/images", get(query_images_handler))
+        // Full-text search over title, alt text, file name and tags
+        .route("/search", get(search_images_handler))
         // Get image by uuid
         .route("/image/:uuid", get(get_image_handler))
+        // Mint a time-limited public URL
+        .route("/image/:uuid/sign", get(sign_image_handler))
         // Upload image to category
         .route("/images/:category", put(put_image_handler))
-        // Delete image by uuid
+        // Upload image to category from a URL
+        .route("/images/:category/fetch", post(fetch_image_handler))
+        // Move image to the trash
         .route("/image/:uuid", delete(delete_image_handler))
+        // Take image out of the trash
+        .route("/image/:uuid/restore", post(restore_image_handler))
+        // List images in the trash, most recently deleted first
+        .route("/trash/:limit/:skip", get(list_trash_handler))
+        // Move image to another category or edit its title and alt text
+        .route("/image/:uuid", patch(patch_image_handler))
+        // Replace the content of an image
+        .route("/image/:uuid/content", put(replace_image_handler))
+        // Tags of an image: list, add, replace all, remove one
+        .route("/image/:uuid/tags", get(get_tags_handler))
+        .route("/image/:uuid/tags", post(add_tags_handler))
+        .route("/image/:uuid/tags", put(set_tags_handler))
+        .route("/image/:uuid/tags/:tag", delete(delete_tag_handler))
+        // Categories with their settings, image counts and storage usage
+        .route("/categories", get(list_categories_handler))
+        .route("/categories", post(create_category_handler))
+        .route("/categories/:name", get(get_category_handler))
+        .route("/categories/:name", patch(patch_category_handler))
+        .route("/categories/:name", delete(delete_category_handler))
+        // Albums: ordered groups of images across categories
+        .route("/albums", get(list_albums_handler))
+        .route("/albums", post(create_album_handler))
+        .route("/album/:uuid", get(get_album_handler))
+        .route("/album/:uuid", patch(patch_album_handler))
+        .route("/album/:uuid", delete(delete_album_handler))
+        // Add images to an album, or replace them all in a new order
+        .route("/album/:uuid/items", post(add_album_items_handler))
+        .route("/album/:uuid/items", put(set_album_items_handler))
+        // Move an image within an album, or take it out
+        .route("/album/:uuid/items/:image", patch(move_album_item_handler))
+        .route(
+            "/album/:uuid/items/:image",
+            delete(remove_album_item_handler),
+        )
+        .route_layer(middleware::from_fn_with_state(state.clone(), authorize))
+        .layer(DefaultBodyLimit::max(body_limit))
         .with_state(state)
-        .layer(DefaultBodyLimit::disable())
-        .layer(DefaultBodyLimit::max(10 * 1000 * 10))
 }
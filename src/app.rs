@@ -1,21 +1,50 @@
-use std::{path::Path, str::FromStr};
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::Path,
+    str::FromStr,
+};
 
-use chrono::Utc;
+use axum::{body::Bytes, http::HeaderValue};
+use chrono::{DateTime, Utc};
 use clap::{Parser, Subcommand};
+use futures_util::{Stream, StreamExt};
+use image::ImageFormat;
 use mime_guess::Mime;
-use rusqlite::Connection;
+use rusqlite::{Connection, OptionalExtension};
 use serde::Serialize;
+use sha2::{Digest, Sha256};
 use tokio::sync::{Mutex, RwLock};
 
-use crate::{variant::Variant, ImagioError};
+use crate::{
+    account::{AccountCommand, ImagioAccount},
+    auth::{TokenCommand, UrlSigner},
+    category::ensure_category,
+    db,
+    fetch::RemoteFetcher,
+    metadata::ImageMetadata,
+    transform::{OutputFormat, TransformSpec},
+    variant::{Variant, VariantPresets},
+    ImagioError,
+};
 use opendal::{services::Fs, Operator};
 
 #[derive(Debug)]
 pub(crate) struct ImagioState {
     pub(crate) db: RwLock<Mutex<Connection>>,
-    pub(crate) slug: String,
     pub(crate) storage: ImagioStorageOperator,
+    pub(crate) variants: VariantPresets,
+    pub(crate) negotiate: Vec<OutputFormat>,
+    pub(crate) cache_control: HeaderValue,
+    pub(crate) private_categories: HashSet<String>,
+    pub(crate) signer: Option<UrlSigner>,
+    pub(crate) max_sign_ttl: u32,
+    pub(crate) max_upload_files: usize,
+    pub(crate) limits: UploadLimits,
+    pub(crate) fetcher: RemoteFetcher,
+    pub(crate) trash_retention: chrono::Duration,
     pub(crate) bind: String,
+    pub(crate) public_url: String,
+    pub(crate) srcset_variants: Vec<(String, TransformSpec)>,
 }
 
 #[derive(Debug, Clone, Subcommand)]
@@ -24,7 +53,24 @@ pub enum ImagioCommand {
         #[clap(short, long, default_value = "false")]
         force: bool,
     },
-    Generate,
+    Generate {
+        #[clap(long)]
+        category: Vec<String>,
+        #[clap(long)]
+        variant: Vec<String>,
+        #[clap(short = 'j', long, default_value = "4")]
+        concurrency: usize,
+        #[clap(long, default_value = "false")]
+        skip_cached: bool,
+    },
+    Account {
+        #[clap(subcommand)]
+        command: AccountCommand,
+    },
+    Token {
+        #[clap(subcommand)]
+        command: TokenCommand,
+    },
     Serve,
 }
 
@@ -65,46 +111,242 @@ pub(crate) struct ImagioCli {
     pub(crate) db: String,
     #[clap(flatten)]
     pub(crate) storage: ImagioStorage,
+    // Account adopting the images and tokens from before accounts existed
     #[clap(long, default_value = "pBxTJTxHRtQetTGf")]
     pub(crate) account_id: String,
     #[clap(long, default_value = "localhost:4000")]
     pub(crate) bind: String,
+    // Base URL the server is reached at, for the URLs in API responses;
+    // `http://<bind>` when not given
+    #[clap(long, default_value = None)]
+    pub(crate) public_url: Option<String>,
+    #[clap(long, default_value = None)]
+    pub(crate) variants: Option<String>,
+    #[clap(long, value_delimiter = ',', num_args = 0.., default_value = "avif,webp")]
+    pub(crate) negotiate_formats: Vec<OutputFormat>,
+    #[clap(long, default_value = "public, max-age=86400")]
+    pub(crate) cache_control: String,
+    #[clap(long = "private-category")]
+    pub(crate) private_categories: Vec<String>,
+    #[clap(long, default_value = None)]
+    pub(crate) signing_key: Option<String>,
+    // Longest validity, in seconds, of a signed URL minted through the API
+    #[clap(long, default_value = "604800")]
+    pub(crate) max_sign_ttl: u32,
+    // Presets, each with its own width, listed in the `srcset` of images
+    #[clap(long = "srcset-variant")]
+    pub(crate) srcset_variants: Vec<String>,
+    // Files accepted per upload request; further files are rejected
+    #[clap(long, default_value = "20")]
+    pub(crate) max_upload_files: usize,
+    // Limits for each uploaded image
+    #[clap(long, default_value = "20000000")]
+    pub(crate) max_upload_bytes: u64,
+    #[clap(long, default_value = "16384")]
+    pub(crate) max_upload_dimension: u32,
+    #[clap(long, default_value = "100000000")]
+    pub(crate) max_upload_pixels: u64,
+    #[clap(long, value_delimiter = ',', value_parser = image_format, default_value = "png,jpeg,webp")]
+    pub(crate) allowed_formats: Vec<ImageFormat>,
+    // Limits for images fetched from a URL
+    #[clap(long, default_value = "20000000")]
+    pub(crate) fetch_max_bytes: u64,
+    #[clap(long, default_value = "10")]
+    pub(crate) fetch_timeout: u64,
+    // Hosts (or `*.domain`) that may be fetched from, even on private
+    // addresses; when given, no other host may
+    #[clap(long = "fetch-allow-host")]
+    pub(crate) fetch_allow_hosts: Vec<String>,
+    #[clap(long = "fetch-deny-host")]
+    pub(crate) fetch_deny_hosts: Vec<String>,
+    // Days deleted images stay in the trash before being purged
+    #[clap(long, default_value = "30")]
+    pub(crate) trash_retention: u32,
     #[clap(subcommand)]
     pub(crate) command: ImagioCommand,
 }
 
+fn image_format(s: &str) -> Result<ImageFormat, String> {
+    ImageFormat::from_extension(s).ok_or_else(|| format!("unknown image format: {}", s))
+}
+
+// Checks applied to every upload, whether sent or fetched
+#[derive(Debug, Clone)]
+pub struct UploadLimits {
+    pub(crate) max_bytes: u64,
+    max_dimension: u32,
+    max_pixels: u64,
+    formats: Vec<ImageFormat>,
+}
+
+impl UploadLimits {
+    fn check_format(&self, format: ImageFormat) -> Result<(), ImagioError> {
+        if !self.formats.contains(&format) {
+            return Err(ImagioError::UnsupportedFormat(
+                format.to_mime_type().to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_dimensions(&self, metadata: &ImageMetadata) -> Result<(), ImagioError> {
+        let (Some(width), Some(height)) = (metadata.width, metadata.height) else {
+            return Err(ImagioError::InvalidImage(
+                "unreadable image header".to_string(),
+            ));
+        };
+        // Refuse decompression bombs before anything tries to decode them
+        if width.max(height) > self.max_dimension || width as u64 * height as u64 > self.max_pixels
+        {
+            return Err(ImagioError::DimensionsTooLarge(width, height));
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ImagioStorageOperator {
     pub(crate) cache: Operator,
     pub(crate) store: Operator,
 }
 
+const SNIFF_LEN: usize = 512;
+// Bytes of an upload kept for reading its headers and EXIF
+const METADATA_LEN: usize = 64 * 1024;
+
+pub(crate) const SELECT_IMAGES: &str =
+    "SELECT images.uuid, images.category, images.mime, images.create_time, \
+    images.sha256, images.account_id, accounts.root, images.size, images.original_filename, \
+    images.width, images.height, images.camera, images.taken_at, images.orientation, \
+    images.latitude, images.longitude, images.blob_id, blobs.path, images.title, images.alt, \
+    images.update_time, images.deleted_at, \
+    (SELECT group_concat(name, ',') FROM (SELECT tags.name FROM image_tags \
+    JOIN tags ON tags.id = image_tags.tag_id WHERE image_tags.image_id = images.id \
+    ORDER BY tags.name)) \
+    FROM images JOIN accounts ON accounts.id = images.account_id \
+    LEFT JOIN blobs ON blobs.id = images.blob_id";
+
 #[derive(Debug, Clone, Serialize)]
 pub struct ImagioImage {
     pub(crate) uuid: String,
     pub(crate) category: String,
-    #[serde(skip)]
+    #[serde(serialize_with = "serialize_mime")]
     pub(crate) mime: Mime,
+    #[serde(skip)]
+    pub(crate) create_time: DateTime<Utc>,
+    pub(crate) sha256: Option<String>,
+    #[serde(skip)]
+    pub(crate) account_id: i64,
+    #[serde(skip)]
+    pub(crate) root: String,
+    pub(crate) size: Option<u64>,
+    pub(crate) original_filename: Option<String>,
+    #[serde(flatten)]
+    pub(crate) metadata: ImageMetadata,
+    // Shared original in the store, for images recorded since deduplication
+    #[serde(skip)]
+    pub(crate) blob_id: Option<i64>,
+    #[serde(skip)]
+    pub(crate) blob: Option<String>,
+    pub(crate) title: Option<String>,
+    pub(crate) alt: Option<String>,
+    #[serde(skip)]
+    pub(crate) update_time: Option<DateTime<Utc>>,
+    pub(crate) tags: Vec<String>,
+    // Set while the image is in the trash
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) deleted_at: Option<DateTime<Utc>>,
+    // URLs the image is served under, filled in for API responses
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) variants: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) srcset: Option<String>,
+}
+
+fn serialize_mime<S: serde::Serializer>(mime: &Mime, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(mime)
 }
 
 impl ImagioImage {
-    pub(crate) fn new(uuid: &str, category: &str, mime: &str) -> Result<Self, ImagioError> {
+    pub(crate) fn new(
+        account: &ImagioAccount,
+        uuid: &str,
+        category: &str,
+        mime: &str,
+    ) -> Result<Self, ImagioError> {
         let mime = Mime::from_str(mime)?;
         Ok(ImagioImage {
             uuid: uuid.to_string(),
             category: category.to_string(),
             mime,
+            create_time: Utc::now(),
+            sha256: None,
+            account_id: account.id,
+            root: account.root.clone(),
+            size: None,
+            original_filename: None,
+            metadata: ImageMetadata::default(),
+            blob_id: None,
+            blob: None,
+            title: None,
+            alt: None,
+            update_time: None,
+            tags: Vec::new(),
+            deleted_at: None,
+            variants: BTreeMap::new(),
+            srcset: None,
         })
     }
 
+    // Time the content last changed, for `Last-Modified`
+    pub(crate) fn modified(&self) -> DateTime<Utc> {
+        self.update_time.unwrap_or(self.create_time)
+    }
+
+    // Directory holding the rendered variants in the cache
+    pub(crate) fn cache_dir(&self) -> String {
+        match self.root.as_str() {
+            "" => format!("{}/{}/", self.category, self.uuid),
+            root => format!("{}/{}/{}/", root, self.category, self.uuid),
+        }
+    }
+
+    // Renders of the current content, so that one finishing after the
+    // content was replaced cannot be served
+    fn content_dir(&self) -> String {
+        let sha256 = self.sha256.as_deref().and_then(|sha256| sha256.get(..8));
+        format!(
+            "{}/{}/{}",
+            self.category,
+            self.uuid,
+            sha256.unwrap_or("unhashed")
+        )
+    }
+
     pub(crate) fn ext(&self) -> String {
         self.mime.subtype().to_string().to_ascii_uppercase()
     }
 
     pub(crate) fn filename(&self, variant: &Variant) -> String {
-        match variant {
+        if let (Variant::Original, Some(blob)) = (variant, &self.blob) {
+            return blob.clone();
+        }
+        let filename = match variant {
             Variant::Original => format!("{}/{}.{}", self.category, self.uuid, self.ext()),
-            var => format!("{}_{}_{}.{}", self.category, self.uuid, var, self.ext()),
+            // Keyed by the spec too, so that renders of a changed preset are
+            // never served
+            Variant::Preset(name, spec) => format!(
+                "{}/{}-{}.{}",
+                self.content_dir(),
+                name,
+                &sha256_hex(spec.to_string().as_bytes())[..8],
+                variant.format(self).ext()
+            ),
+            var => format!("{}/{}.{}", self.content_dir(), var, var.format(self).ext()),
+        };
+        match self.root.as_str() {
+            "" => filename,
+            root => format!("{}/{}", root, filename),
         }
     }
 
@@ -120,6 +362,124 @@ impl ImagioImage {
     }
 }
 
+fn insert_blob(
+    conn: &Connection,
+    account_id: i64,
+    sha256: &Option<String>,
+    path: &str,
+) -> Result<i64, ImagioError> {
+    conn.execute(
+        "INSERT INTO blobs (account_id, sha256, path, refs) VALUES (?, ?, ?, 1)",
+        (account_id, sha256, path),
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+// Point the image at the account's blob with the same content, or at a new
+// blob for the original just written to `path`. Returns whether `path` became
+// redundant.
+pub(crate) fn attach_blob(
+    conn: &Connection,
+    image: &mut ImagioImage,
+    path: &str,
+) -> Result<bool, ImagioError> {
+    let mut blob = conn
+        .query_row(
+            "SELECT id, path FROM blobs WHERE account_id = ? AND sha256 = ?",
+            (image.account_id, &image.sha256),
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)),
+        )
+        .optional()?;
+    if blob.is_none() {
+        // Images from before deduplication own their original
+        let mut stmt = conn.prepare(&format!(
+            "{} WHERE images.account_id = ? AND images.sha256 = ? AND images.blob_id IS NULL \
+            AND images.uuid != ? LIMIT 1",
+            SELECT_IMAGES
+        ))?;
+        let mut rows = stmt.query((image.account_id, &image.sha256, &image.uuid))?;
+        if let Some(row) = rows.next()? {
+            let legacy = ImagioImage::try_from(row)?;
+            let legacy_path = legacy.filename(&Variant::Original);
+            let blob_id = insert_blob(conn, legacy.account_id, &legacy.sha256, &legacy_path)?;
+            conn.execute(
+                "UPDATE images SET blob_id = ? WHERE uuid = ?",
+                (blob_id, &legacy.uuid),
+            )?;
+            blob = Some((blob_id, legacy_path));
+        }
+    }
+
+    let (blob_id, blob_path, redundant) = match blob {
+        Some((blob_id, blob_path)) => {
+            conn.execute("UPDATE blobs SET refs = refs + 1 WHERE id = ?", [blob_id])?;
+            (blob_id, blob_path, true)
+        }
+        None => {
+            let blob_id = insert_blob(conn, image.account_id, &image.sha256, path)?;
+            (blob_id, path.to_string(), false)
+        }
+    };
+    image.blob_id = Some(blob_id);
+    image.blob = Some(blob_path);
+    Ok(redundant)
+}
+
+// Drop the image's reference to its original. Returns the original's path
+// once nothing refers to it any more.
+pub(crate) fn release_blob(
+    conn: &Connection,
+    image: &ImagioImage,
+) -> Result<Option<String>, ImagioError> {
+    let Some(blob_id) = image.blob_id else {
+        return Ok(Some(image.filename(&Variant::Original)));
+    };
+    conn.execute("UPDATE blobs SET refs = refs - 1 WHERE id = ?", [blob_id])?;
+    let removed = conn.execute("DELETE FROM blobs WHERE id = ? AND refs <= 0", [blob_id])?;
+    Ok((removed > 0).then(|| image.filename(&Variant::Original)))
+}
+
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+// Base of absolute URLs, without the trailing slash. It may have a path,
+// for a server behind a proxy under a prefix.
+fn public_url(url: Option<&str>, bind: &str) -> Result<String, ImagioError> {
+    let Some(url) = url else {
+        return Ok(format!("http://{}", bind));
+    };
+    let parsed =
+        url::Url::parse(url).map_err(|e| ImagioError::ConfigError(format!("public url: {}", e)))?;
+    if !matches!(parsed.scheme(), "http" | "https")
+        || parsed.query().is_some()
+        || parsed.fragment().is_some()
+    {
+        return Err(ImagioError::ConfigError(format!(
+            "public url must be a plain http(s) URL: {}",
+            url
+        )));
+    }
+    Ok(parsed.as_str().trim_end_matches('/').to_string())
+}
+
+// Presets of a srcset, whose widths depend on each image
+fn srcset_variants(
+    presets: &VariantPresets,
+    names: &[String],
+) -> Result<Vec<(String, TransformSpec)>, ImagioError> {
+    names
+        .iter()
+        .map(|name| match presets.resolve(name) {
+            Ok(Variant::Preset(name, spec)) => Ok((name, spec)),
+            _ => Err(ImagioError::ConfigError(format!(
+                "srcset variant must be a preset: {}",
+                name
+            ))),
+        })
+        .collect()
+}
+
 impl TryFrom<&rusqlite::Row<'_>> for ImagioImage {
     type Error = ImagioError;
 
@@ -127,10 +487,44 @@ impl TryFrom<&rusqlite::Row<'_>> for ImagioImage {
         let uuid: String = row.get(0)?;
         let category: String = row.get(1)?;
         let mime: String = row.get(2)?;
+        let create_time: String = row.get(3)?;
         let image = ImagioImage {
             uuid: uuid.to_string(),
             category,
             mime: Mime::from_str(&mime)?,
+            create_time: create_time.parse()?,
+            sha256: row.get(4)?,
+            account_id: row.get(5)?,
+            root: row.get(6)?,
+            size: row.get(7)?,
+            original_filename: row.get(8)?,
+            metadata: ImageMetadata {
+                width: row.get(9)?,
+                height: row.get(10)?,
+                camera: row.get(11)?,
+                taken_at: row.get(12)?,
+                orientation: row.get(13)?,
+                latitude: row.get(14)?,
+                longitude: row.get(15)?,
+            },
+            blob_id: row.get(16)?,
+            blob: row.get(17)?,
+            title: row.get(18)?,
+            alt: row.get(19)?,
+            update_time: row
+                .get::<_, Option<String>>(20)?
+                .map(|t| t.parse())
+                .transpose()?,
+            deleted_at: row
+                .get::<_, Option<String>>(21)?
+                .map(|t| t.parse())
+                .transpose()?,
+            tags: row
+                .get::<_, Option<String>>(22)?
+                .map(|tags| tags.split(',').map(str::to_string).collect())
+                .unwrap_or_default(),
+            variants: BTreeMap::new(),
+            srcset: None,
         };
         Ok(image)
     }
@@ -138,7 +532,8 @@ impl TryFrom<&rusqlite::Row<'_>> for ImagioImage {
 
 impl ImagioState {
     pub(crate) fn new(cli: ImagioCli) -> Result<Self, ImagioError> {
-        let db = Connection::open(&cli.db).unwrap();
+        let db = db::open(&cli.db)?;
+        ImagioAccount::adopt_legacy(&db, &cli.account_id)?;
         let db = RwLock::new(Mutex::new(db));
 
         let storage = match &cli.storage.backend {
@@ -185,18 +580,90 @@ impl ImagioState {
             }
         };
 
+        let variants = match &cli.variants {
+            Some(path) => VariantPresets::load(path)?,
+            None => VariantPresets::default(),
+        };
+
+        let signer = cli.signing_key.as_deref().map(UrlSigner::new);
+        if signer.is_none() && !cli.private_categories.is_empty() {
+            return Err(ImagioError::ConfigError(
+                "private categories require --signing-key".to_string(),
+            ));
+        }
+        if cli.max_sign_ttl == 0 {
+            return Err(ImagioError::ConfigError(
+                "max sign ttl must be positive".to_string(),
+            ));
+        }
+
+        // Also bounded by how far back a timestamp can go, so that the purge
+        // cutoff can always be computed
+        let trash_retention = chrono::Duration::try_days(cli.trash_retention.into())
+            .filter(|retention| chrono::Utc::now().checked_sub_signed(*retention).is_some())
+            .ok_or_else(|| ImagioError::ConfigError("trash retention out of range".to_string()))?;
+
+        let public_url = public_url(cli.public_url.as_deref(), &cli.bind)?;
+        let srcset_variants = srcset_variants(&variants, &cli.srcset_variants)?;
+
         Ok(ImagioState {
             db,
-            slug: cli.account_id,
             storage,
+            variants,
+            negotiate: cli.negotiate_formats,
+            cache_control: HeaderValue::from_str(&cli.cache_control)
+                .map_err(|e| ImagioError::ConfigError(format!("cache control: {}", e)))?,
+            private_categories: cli.private_categories.into_iter().collect(),
+            signer,
+            max_sign_ttl: cli.max_sign_ttl,
+            max_upload_files: cli.max_upload_files,
+            limits: UploadLimits {
+                max_bytes: cli.max_upload_bytes,
+                max_dimension: cli.max_upload_dimension,
+                max_pixels: cli.max_upload_pixels,
+                formats: cli.allowed_formats,
+            },
+            fetcher: RemoteFetcher::new(
+                cli.fetch_max_bytes,
+                std::time::Duration::from_secs(cli.fetch_timeout),
+                &cli.fetch_allow_hosts,
+                &cli.fetch_deny_hosts,
+            ),
+            trash_retention,
             bind: cli.bind,
+            public_url,
+            srcset_variants,
         })
     }
 
-    pub(crate) async fn get(&self, uuid: &str) -> Result<ImagioImage, ImagioError> {
+    pub(crate) async fn get(
+        &self,
+        account: &ImagioAccount,
+        uuid: &str,
+    ) -> Result<ImagioImage, ImagioError> {
+        let lock = self.db.read().await;
+        let conn = &lock.lock().await;
+        let mut stmt = conn.prepare(&format!(
+            "{} WHERE images.account_id = ? AND images.uuid = ? AND images.deleted_at IS NULL",
+            SELECT_IMAGES
+        ))?;
+        let mut rows = stmt.query((account.id, uuid))?;
+
+        if let Some(row) = rows.next()? {
+            let image = ImagioImage::try_from(row)?;
+            return Ok(image);
+        }
+        Err(ImagioError::NotFound)
+    }
+
+    // Look up an image of any account, for the public routes.
+    pub(crate) async fn find(&self, uuid: &str) -> Result<ImagioImage, ImagioError> {
         let lock = self.db.read().await;
         let conn = &lock.lock().await;
-        let mut stmt = conn.prepare("SELECT uuid, category, mime FROM images WHERE uuid = ?")?;
+        let mut stmt = conn.prepare(&format!(
+            "{} WHERE images.uuid = ? AND images.deleted_at IS NULL",
+            SELECT_IMAGES
+        ))?;
         let mut rows = stmt.query([&uuid])?;
 
         if let Some(row) = rows.next()? {
@@ -206,68 +673,242 @@ impl ImagioState {
         Err(ImagioError::NotFound)
     }
 
-    pub(crate) async fn put(&self, image: &ImagioImage) -> Result<(), ImagioError> {
+    // Record an uploaded image, deduplicating by content hash within the
+    // account: the same content in the same category yields the existing
+    // image, in another category a new image sharing the existing blob.
+    // Returns the recorded image and whether the uploaded original became
+    // redundant.
+    async fn put(&self, mut image: ImagioImage) -> Result<(ImagioImage, bool), ImagioError> {
         let lock = self.db.write().await;
-        let conn = &lock.lock().await;
-        let mut stmt = conn.prepare(
-            "INSERT INTO images (uuid, category, mime, create_time) VALUES (?, ?, ?, ?)",
+        let conn = &mut lock.lock().await;
+        let tx = conn.transaction()?;
+
+        let existing = {
+            let mut stmt = tx.prepare(&format!(
+                "{} WHERE images.account_id = ? AND images.sha256 = ? AND images.category = ? \
+                AND images.deleted_at IS NULL ORDER BY images.id LIMIT 1",
+                SELECT_IMAGES
+            ))?;
+            let mut rows = stmt.query((image.account_id, &image.sha256, &image.category))?;
+            rows.next()?.map(ImagioImage::try_from).transpose()?
+        };
+        if let Some(existing) = existing {
+            return Ok((existing, true));
+        }
+        ensure_category(&tx, image.account_id, &image.category)?;
+        let path = image.filename(&Variant::Original);
+        let redundant = attach_blob(&tx, &mut image, &path)?;
+
+        let metadata = &image.metadata;
+        tx.execute(
+            "INSERT INTO images (uuid, category, mime, create_time, sha256, account_id, size, \
+            original_filename, width, height, camera, taken_at, orientation, latitude, longitude, \
+            blob_id, title, alt) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            rusqlite::params![
+                &image.uuid,
+                &image.category,
+                &image.mime.to_string(),
+                &image.create_time.to_string(),
+                &image.sha256,
+                &image.account_id,
+                &image.size,
+                &image.original_filename,
+                &metadata.width,
+                &metadata.height,
+                &metadata.camera,
+                &metadata.taken_at,
+                &metadata.orientation,
+                &metadata.latitude,
+                &metadata.longitude,
+                &image.blob_id,
+                &image.title,
+                &image.alt,
+            ],
         )?;
-        let _ = stmt.execute([
-            &image.uuid,
-            &image.category,
-            &image.mime.to_string(),
-            &Utc::now().to_string(),
-        ])?;
-        Ok(())
+        tx.commit()?;
+        Ok((image, redundant))
     }
 
-    pub(crate) async fn delete(&self, uuid: &str) -> Result<ImagioImage, ImagioError> {
-        let image = {
-            let lock = self.db.read().await;
-            let conn = &lock.lock().await;
-            let mut stmt =
-                conn.prepare("SELECT uuid, category, mime FROM images WHERE uuid = ?")?;
-            let mut rows = stmt.query([&uuid])?;
+    // Stream an upload into the store, then record it in the database.
+    pub(crate) async fn upload<S, E>(
+        &self,
+        account: &ImagioAccount,
+        category: &str,
+        original_filename: Option<String>,
+        stream: S,
+    ) -> Result<ImagioImage, ImagioError>
+    where
+        S: Stream<Item = Result<Bytes, E>>,
+        ImagioError: From<E>,
+    {
+        let mut image = self.store_original(account, category, stream).await?;
+        image.original_filename = original_filename;
 
-            if let Some(row) = rows.next()? {
-                ImagioImage::try_from(row)?
-            } else {
-                return Err(ImagioError::NotFound);
+        let filename = image.filename(&Variant::Original);
+        let (image, redundant) = match self.put(image).await {
+            Ok(recorded) => recorded,
+            Err(err) => {
+                self.storage.store.delete(&filename).await.ok();
+                return Err(err);
             }
         };
+        if redundant {
+            self.storage.store.delete(&filename).await?;
+            tracing::info!("Duplicate of {:?}, removed: {:?}", image.uuid, &filename);
+        }
+        Ok(image)
+    }
+
+    // Stream an original into the store under a new UUID, sniffing the format
+    // from its first bytes, hashing it on the way and reading the metadata
+    // from its headers. Originals breaking the limits are removed again.
+    // Returns an image for the original that is not recorded yet.
+    pub(crate) async fn store_original<S, E>(
+        &self,
+        account: &ImagioAccount,
+        category: &str,
+        stream: S,
+    ) -> Result<ImagioImage, ImagioError>
+    where
+        S: Stream<Item = Result<Bytes, E>>,
+        ImagioError: From<E>,
+    {
+        let max_bytes = match self.category(account.id, category).await?.max_upload_bytes {
+            Some(max_bytes) => max_bytes.min(self.limits.max_bytes),
+            None => self.limits.max_bytes,
+        };
+        let mut stream = std::pin::pin!(stream);
+        let mut head = Vec::new();
+        while head.len() < SNIFF_LEN {
+            match stream.next().await {
+                Some(chunk) => head.extend_from_slice(&chunk?),
+                None => break,
+            }
+        }
+        let format = image::guess_format(&head)
+            .map_err(|_| ImagioError::UnsupportedFormat("unrecognised data".to_string()))?;
+        self.limits.check_format(format)?;
+        let uuid = uuid::Uuid::new_v4().to_string();
+        let mut image = ImagioImage::new(account, &uuid, category, format.to_mime_type())?;
 
-        // Delete the image from the store
         let filename = image.filename(&Variant::Original);
-        self.storage.store.delete(&filename).await?;
-        tracing::info!("Image deleted from: {:?} (Store)", filename);
-
-        // Delete the image from the database
-        {
-            let lock = self.db.write().await;
-            let conn = &lock.lock().await;
-            let mut stmt = conn.prepare("DELETE FROM images WHERE uuid = ?")?;
-            let _ = stmt.execute([&uuid])?;
+        let mut writer = self.storage.store.writer(&filename).await?;
+        let mut hasher = Sha256::new();
+        let mut prefix = Vec::new();
+        let mut size = 0;
+        let written = async {
+            let mut chunk = Bytes::from(head);
+            loop {
+                hasher.update(&chunk);
+                size += chunk.len() as u64;
+                if size > max_bytes {
+                    return Err(ImagioError::TooLarge(max_bytes));
+                }
+                if prefix.len() < METADATA_LEN {
+                    let take = chunk.len().min(METADATA_LEN - prefix.len());
+                    prefix.extend_from_slice(&chunk[..take]);
+                }
+                writer.write(chunk).await?;
+                match stream.next().await {
+                    Some(next) => chunk = next?,
+                    None => break,
+                }
+            }
+            writer.close().await?;
+            Ok::<_, ImagioError>(())
         }
+        .await;
+        if let Err(err) = written {
+            // Not every backend discards what was already written on abort
+            writer.abort().await.ok();
+            self.storage.store.delete(&filename).await.ok();
+            return Err(err);
+        }
+        tracing::info!("Image saved to: {:?}", &filename);
 
+        let metadata = async {
+            let mut metadata = ImageMetadata::read(&prefix);
+            if metadata.width.is_none() && size > prefix.len() as u64 {
+                // Headers extend past the prefix, e.g. behind a large EXIF thumbnail
+                let original = self.storage.store.read(&filename).await?;
+                metadata = ImageMetadata::read(&original.to_vec());
+            }
+            self.limits.check_dimensions(&metadata)?;
+            Ok::<_, ImagioError>(metadata)
+        }
+        .await;
+        image.metadata = match metadata {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                self.storage.store.delete(&filename).await.ok();
+                return Err(err);
+            }
+        };
+        image.sha256 = Some(format!("{:x}", hasher.finalize()));
+        image.size = Some(size);
         Ok(image)
     }
 
+    // Content hash of the original, computed and saved on first use for
+    // images uploaded before hashes were recorded.
+    pub(crate) async fn original_sha256(
+        &self,
+        image: &ImagioImage,
+        original: &[u8],
+    ) -> Result<String, ImagioError> {
+        if let Some(sha256) = &image.sha256 {
+            return Ok(sha256.clone());
+        }
+        let sha256 = sha256_hex(original);
+        let lock = self.db.write().await;
+        let conn = &lock.lock().await;
+        conn.execute(
+            "UPDATE images SET sha256 = ? WHERE uuid = ?",
+            (&sha256, &image.uuid),
+        )?;
+        Ok(sha256)
+    }
+
     pub(crate) async fn list(
         &self,
+        account: &ImagioAccount,
         category: String,
         limit: usize,
         skip: usize,
     ) -> Result<Vec<ImagioImage>, ImagioError> {
         let lock = self.db.read().await;
         let conn = &lock.lock().await;
-        let mut stmt = conn.prepare(
-            "SELECT uuid, category, mime FROM images WHERE category = ? ORDER BY create_time DESC LIMIT ? OFFSET ?",
-        )?;
-        let mut rows = stmt.query([
-            category,
-            (limit as i64).to_string(),
-            (skip as i64).to_string(),
-        ])?;
+        let mut stmt = conn.prepare(&format!(
+            "{} WHERE images.account_id = ? AND images.category = ? \
+            AND images.deleted_at IS NULL ORDER BY images.create_time DESC LIMIT ? OFFSET ?",
+            SELECT_IMAGES
+        ))?;
+        let mut rows = stmt.query((account.id, category, limit as i64, skip as i64))?;
+
+        let mut images = Vec::new();
+        while let Some(row) = rows.next()? {
+            let image = ImagioImage::try_from(row)?;
+            images.push(image);
+        }
+
+        Ok(images)
+    }
+
+    pub(crate) async fn list_all(
+        &self,
+        categories: &[String],
+    ) -> Result<Vec<ImagioImage>, ImagioError> {
+        let lock = self.db.read().await;
+        let conn = &lock.lock().await;
+        let filter = match categories.len() {
+            0 => String::new(),
+            n => format!("AND images.category IN ({})", vec!["?"; n].join(", ")),
+        };
+        let mut stmt = conn.prepare(&format!(
+            "{} WHERE images.deleted_at IS NULL {} ORDER BY images.id",
+            SELECT_IMAGES, filter
+        ))?;
+        let mut rows = stmt.query(rusqlite::params_from_iter(categories))?;
 
         let mut images = Vec::new();
         while let Some(row) = rows.next()? {
@@ -278,3 +919,147 @@ impl ImagioState {
         Ok(images)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deletion::{schedule_deletion, Storage};
+
+    fn setup() -> (Connection, ImagioAccount) {
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::migrate(&mut conn).unwrap();
+        conn.execute(
+            "INSERT INTO accounts (slug, root, create_time) VALUES ('test', '', ?)",
+            [Utc::now().to_string()],
+        )
+        .unwrap();
+        let account = ImagioAccount {
+            id: conn.last_insert_rowid(),
+            slug: "test".to_string(),
+            root: String::new(),
+            create_time: Utc::now(),
+        };
+        (conn, account)
+    }
+
+    fn image(account: &ImagioAccount, category: &str, sha256: &str) -> ImagioImage {
+        let uuid = uuid::Uuid::new_v4().to_string();
+        let mut image = ImagioImage::new(account, &uuid, category, "image/png").unwrap();
+        image.sha256 = Some(sha256.to_string());
+        image
+    }
+
+    fn insert(conn: &Connection, image: &ImagioImage) {
+        conn.execute(
+            "INSERT INTO images (uuid, category, mime, create_time, sha256, account_id, blob_id) \
+            VALUES (?, ?, ?, ?, ?, ?, ?)",
+            rusqlite::params![
+                &image.uuid,
+                &image.category,
+                &image.mime.to_string(),
+                &image.create_time.to_string(),
+                &image.sha256,
+                &image.account_id,
+                &image.blob_id,
+            ],
+        )
+        .unwrap();
+    }
+
+    // Upload as `put` records it: the original written to its own path first
+    fn upload(conn: &Connection, image: &mut ImagioImage) -> bool {
+        let path = image.filename(&Variant::Original);
+        let redundant = attach_blob(conn, image, &path).unwrap();
+        insert(conn, image);
+        redundant
+    }
+
+    fn refs(conn: &Connection, blob_id: i64) -> Option<i64> {
+        conn.query_row("SELECT refs FROM blobs WHERE id = ?", [blob_id], |row| {
+            row.get(0)
+        })
+        .optional()
+        .unwrap()
+    }
+
+    fn scheduled(conn: &Connection) -> Vec<String> {
+        let mut stmt = conn
+            .prepare("SELECT path FROM deletions WHERE storage = 'store' ORDER BY id")
+            .unwrap();
+        let rows = stmt.query_map([], |row| row.get(0)).unwrap();
+        rows.collect::<Result<_, _>>().unwrap()
+    }
+
+    // What purging an image does with its original
+    fn purge(conn: &Connection, image: &ImagioImage) {
+        if let Some(filename) = release_blob(conn, image).unwrap() {
+            schedule_deletion(conn, Storage::Store, &filename).unwrap();
+        }
+    }
+
+    #[test]
+    fn duplicate_uploads_share_a_blob() {
+        let (conn, account) = setup();
+        let mut first = image(&account, "cats", "aaa");
+        assert!(!upload(&conn, &mut first));
+        let mut second = image(&account, "dogs", "aaa");
+        assert!(upload(&conn, &mut second));
+
+        assert_eq!(first.blob_id, second.blob_id);
+        assert_eq!(second.blob, Some(first.filename(&Variant::Original)));
+        assert_eq!(refs(&conn, first.blob_id.unwrap()), Some(2));
+
+        let mut other = image(&account, "cats", "bbb");
+        assert!(!upload(&conn, &mut other));
+        assert_ne!(other.blob_id, first.blob_id);
+    }
+
+    #[test]
+    fn legacy_images_are_adopted() {
+        let (conn, account) = setup();
+        let legacy = image(&account, "cats", "aaa");
+        insert(&conn, &legacy);
+        let legacy_path = legacy.filename(&Variant::Original);
+
+        let mut fresh = image(&account, "dogs", "aaa");
+        assert!(upload(&conn, &mut fresh));
+        assert_eq!(fresh.blob, Some(legacy_path.clone()));
+        let blob_id = fresh.blob_id.unwrap();
+        assert_eq!(refs(&conn, blob_id), Some(2));
+        let adopted: Option<i64> = conn
+            .query_row(
+                "SELECT blob_id FROM images WHERE uuid = ?",
+                [&legacy.uuid],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(adopted, Some(blob_id));
+    }
+
+    #[test]
+    fn originals_are_deleted_with_the_last_reference() {
+        let (conn, account) = setup();
+        let mut first = image(&account, "cats", "aaa");
+        upload(&conn, &mut first);
+        let mut second = image(&account, "dogs", "aaa");
+        upload(&conn, &mut second);
+        let blob_id = first.blob_id.unwrap();
+
+        purge(&conn, &second);
+        assert_eq!(refs(&conn, blob_id), Some(1));
+        assert!(scheduled(&conn).is_empty());
+
+        purge(&conn, &first);
+        assert_eq!(refs(&conn, blob_id), None);
+        assert_eq!(scheduled(&conn), vec![first.filename(&Variant::Original)]);
+    }
+
+    #[test]
+    fn legacy_originals_are_deleted_with_their_image() {
+        let (conn, account) = setup();
+        let legacy = image(&account, "cats", "aaa");
+        insert(&conn, &legacy);
+        purge(&conn, &legacy);
+        assert_eq!(scheduled(&conn), vec![legacy.filename(&Variant::Original)]);
+    }
+}
@@ -0,0 +1,442 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, Method},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use clap::Subcommand;
+use hmac::{Hmac, Mac};
+use rusqlite::Connection;
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::{account::ImagioAccount, app::sha256_hex, extract::Path, ImagioError, ImagioState};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Scope {
+    Read,
+    Upload,
+    Delete,
+}
+
+impl Scope {
+    // Scope a request needs, derived from its method
+    fn required(method: &Method) -> Scope {
+        match *method {
+            Method::GET | Method::HEAD => Scope::Read,
+            Method::DELETE => Scope::Delete,
+            _ => Scope::Upload,
+        }
+    }
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Scope::Read => write!(f, "read"),
+            Scope::Upload => write!(f, "upload"),
+            Scope::Delete => write!(f, "delete"),
+        }
+    }
+}
+
+impl std::str::FromStr for Scope {
+    type Err = ImagioError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Scope::Read),
+            "upload" => Ok(Scope::Upload),
+            "delete" => Ok(Scope::Delete),
+            _ => Err(ImagioError::ConfigError(format!("unknown scope: {}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum TokenCommand {
+    Create {
+        #[clap(long)]
+        name: String,
+        // Account slug, defaults to `--account-id`
+        #[clap(long)]
+        account: Option<String>,
+        #[clap(long = "scope", value_enum, required = true)]
+        scopes: Vec<Scope>,
+    },
+    List,
+    Revoke {
+        id: i64,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct ImagioToken {
+    pub(crate) id: i64,
+    pub(crate) name: String,
+    pub(crate) scopes: Vec<Scope>,
+    pub(crate) create_time: DateTime<Utc>,
+    pub(crate) revoke_time: Option<DateTime<Utc>>,
+    pub(crate) account_id: i64,
+}
+
+const TOKEN_COLUMNS: &str = "id, name, scopes, create_time, revoke_time, account_id";
+
+impl TryFrom<&rusqlite::Row<'_>> for ImagioToken {
+    type Error = ImagioError;
+
+    fn try_from(row: &rusqlite::Row) -> Result<Self, Self::Error> {
+        let scopes: String = row.get(2)?;
+        let create_time: String = row.get(3)?;
+        let revoke_time: Option<String> = row.get(4)?;
+        Ok(ImagioToken {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            scopes: scopes
+                .split(',')
+                .map(str::parse)
+                .collect::<Result<_, _>>()?,
+            create_time: create_time.parse()?,
+            revoke_time: revoke_time.map(|t| t.parse()).transpose()?,
+            account_id: row.get(5)?,
+        })
+    }
+}
+
+impl ImagioToken {
+    // Returns the new token together with its secret, which is only stored
+    // hashed and cannot be recovered later.
+    pub(crate) fn create(
+        conn: &Connection,
+        account: &ImagioAccount,
+        name: &str,
+        scopes: &[Scope],
+    ) -> Result<(ImagioToken, String), ImagioError> {
+        let secret = format!(
+            "imagio_{}{}",
+            uuid::Uuid::new_v4().simple(),
+            uuid::Uuid::new_v4().simple()
+        );
+        let scopes = scopes
+            .iter()
+            .map(Scope::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
+        conn.execute(
+            "INSERT INTO tokens (name, secret_hash, scopes, create_time, account_id) \
+            VALUES (?, ?, ?, ?, ?)",
+            (
+                name,
+                sha256_hex(secret.as_bytes()),
+                scopes,
+                Utc::now().to_string(),
+                account.id,
+            ),
+        )?;
+        let token = ImagioToken::get(conn, conn.last_insert_rowid())?;
+        Ok((token, secret))
+    }
+
+    fn get(conn: &Connection, id: i64) -> Result<ImagioToken, ImagioError> {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM tokens WHERE id = ?",
+            TOKEN_COLUMNS
+        ))?;
+        let mut rows = stmt.query([id])?;
+        match rows.next()? {
+            Some(row) => ImagioToken::try_from(row),
+            None => Err(ImagioError::NotFound),
+        }
+    }
+
+    pub(crate) fn list(conn: &Connection) -> Result<Vec<ImagioToken>, ImagioError> {
+        let mut stmt =
+            conn.prepare(&format!("SELECT {} FROM tokens ORDER BY id", TOKEN_COLUMNS))?;
+        let mut rows = stmt.query([])?;
+
+        let mut tokens = Vec::new();
+        while let Some(row) = rows.next()? {
+            tokens.push(ImagioToken::try_from(row)?);
+        }
+        Ok(tokens)
+    }
+
+    pub(crate) fn revoke(conn: &Connection, id: i64) -> Result<(), ImagioError> {
+        let updated = conn.execute(
+            "UPDATE tokens SET revoke_time = ? WHERE id = ? AND revoke_time IS NULL",
+            (Utc::now().to_string(), id),
+        )?;
+        if updated == 0 {
+            return Err(ImagioError::NotFound);
+        }
+        Ok(())
+    }
+
+    fn find(conn: &Connection, secret: &str) -> Result<Option<ImagioToken>, ImagioError> {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM tokens WHERE secret_hash = ? AND revoke_time IS NULL",
+            TOKEN_COLUMNS
+        ))?;
+        let mut rows = stmt.query([sha256_hex(secret.as_bytes())])?;
+        rows.next()?.map(ImagioToken::try_from).transpose()
+    }
+}
+
+pub(crate) fn token_command(
+    conn: &Connection,
+    default_account: &str,
+    command: TokenCommand,
+) -> Result<(), ImagioError> {
+    match command {
+        TokenCommand::Create {
+            name,
+            account,
+            scopes,
+        } => {
+            let account = ImagioAccount::find(conn, account.as_deref().unwrap_or(default_account))?;
+            let (token, secret) = ImagioToken::create(conn, &account, &name, &scopes)?;
+            tracing::info!(
+                "Created token {} ({}) for account {}",
+                token.id,
+                token.name,
+                account.slug
+            );
+            println!("{}", secret);
+        }
+        TokenCommand::List => {
+            let accounts = ImagioAccount::list(conn)?
+                .into_iter()
+                .map(|account| (account.id, account.slug))
+                .collect::<HashMap<_, _>>();
+            for token in ImagioToken::list(conn)? {
+                let scopes = token
+                    .scopes
+                    .iter()
+                    .map(Scope::to_string)
+                    .collect::<Vec<_>>();
+                let status = match token.revoke_time {
+                    Some(time) => format!("revoked {}", time),
+                    None => "active".to_string(),
+                };
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    token.id,
+                    accounts.get(&token.account_id).cloned().unwrap_or_default(),
+                    token.name,
+                    scopes.join(","),
+                    token.create_time,
+                    status
+                );
+            }
+        }
+        TokenCommand::Revoke { id } => {
+            ImagioToken::revoke(conn, id)?;
+            tracing::info!("Revoked token {}", id);
+        }
+    }
+    Ok(())
+}
+
+impl ImagioState {
+    pub(crate) async fn authenticate(&self, secret: &str) -> Result<ImagioToken, ImagioError> {
+        let lock = self.db.read().await;
+        let conn = &lock.lock().await;
+        ImagioToken::find(conn, secret)?.ok_or(ImagioError::Unauthorized)
+    }
+}
+
+// Secret of an `Authorization: Bearer <token>` header, whose scheme is
+// case-insensitive.
+fn bearer_token(value: &str) -> Option<&str> {
+    let (scheme, secret) = value.split_once(' ')?;
+    let secret = secret.trim();
+    (scheme.eq_ignore_ascii_case("bearer") && !secret.is_empty()).then_some(secret)
+}
+
+// Require a bearer token of the account named in the path, carrying the scope
+// implied by the request method. The account is handed on to the handlers as
+// a request extension.
+pub(crate) async fn authorize(
+    State(state): State<Arc<ImagioState>>,
+    Path(params): Path<HashMap<String, String>>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, ImagioError> {
+    let secret = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(bearer_token)
+        .ok_or(ImagioError::Unauthorized)?;
+    let token = state.authenticate(secret).await?;
+    let account = match params.get("account") {
+        Some(slug) => state.account(slug).await.map_err(|err| match err {
+            ImagioError::NotFound => ImagioError::Unauthorized,
+            err => err,
+        })?,
+        None => return Err(ImagioError::Unauthorized),
+    };
+    if token.account_id != account.id {
+        return Err(ImagioError::Unauthorized);
+    }
+
+    let scope = Scope::required(request.method());
+    if !token.scopes.contains(&scope) {
+        return Err(ImagioError::Forbidden(scope));
+    }
+    request.extensions_mut().insert(account);
+    Ok(next.run(request).await)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SignatureQuery {
+    pub(crate) expires: Option<i64>,
+    pub(crate) signature: Option<String>,
+}
+
+// HMAC-SHA256 signatures for time-limited public URLs of the form
+// `/:uuid/:variant?expires=<unix time>&signature=<hex>`.
+#[derive(Debug, Clone)]
+pub struct UrlSigner {
+    key: Vec<u8>,
+}
+
+impl UrlSigner {
+    pub(crate) fn new(key: &str) -> Self {
+        UrlSigner {
+            key: key.as_bytes().to_vec(),
+        }
+    }
+
+    fn mac(&self, uuid: &str, variant: &str, expires: i64) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        mac.update(format!("{}/{}:{}", uuid, variant, expires).as_bytes());
+        mac
+    }
+
+    pub(crate) fn sign(&self, uuid: &str, variant: &str, expires: i64) -> String {
+        hex::encode(self.mac(uuid, variant, expires).finalize().into_bytes())
+    }
+
+    // Path of a variant with a signature that expires after `ttl` seconds
+    pub(crate) fn signed_path(&self, uuid: &str, variant: &str, ttl: u32) -> (String, i64) {
+        let expires = Utc::now().timestamp() + ttl as i64;
+        let signature = self.sign(uuid, variant, expires);
+        let path = format!(
+            "/{}/{}?expires={}&signature={}",
+            uuid, variant, expires, signature
+        );
+        (path, expires)
+    }
+
+    pub(crate) fn verify(
+        &self,
+        uuid: &str,
+        variant: &str,
+        query: &SignatureQuery,
+    ) -> Result<(), ImagioError> {
+        let (Some(expires), Some(signature)) = (query.expires, &query.signature) else {
+            return Err(ImagioError::InvalidSignature);
+        };
+        if expires < Utc::now().timestamp() {
+            return Err(ImagioError::InvalidSignature);
+        }
+        let signature = hex::decode(signature).map_err(|_| ImagioError::InvalidSignature)?;
+        self.mac(uuid, variant, expires)
+            .verify_slice(&signature)
+            .map_err(|_| ImagioError::InvalidSignature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UUID: &str = "0366591c-a5d8-487b-924c-b3314abb91f3";
+
+    fn query(expires: Option<i64>, signature: Option<&str>) -> SignatureQuery {
+        SignatureQuery {
+            expires,
+            signature: signature.map(str::to_string),
+        }
+    }
+
+    fn signed(signer: &UrlSigner, expires: i64) -> SignatureQuery {
+        let signature = signer.sign(UUID, "thumb", expires);
+        query(Some(expires), Some(&signature))
+    }
+
+    #[test]
+    fn parses_bearer_tokens() {
+        assert_eq!(bearer_token("Bearer secret"), Some("secret"));
+        assert_eq!(bearer_token("bearer secret"), Some("secret"));
+        assert_eq!(bearer_token("BEARER  secret "), Some("secret"));
+        assert_eq!(bearer_token("Basic secret"), None);
+        assert_eq!(bearer_token("Bearer"), None);
+        assert_eq!(bearer_token("Bearer "), None);
+        assert_eq!(bearer_token("Bearersecret"), None);
+    }
+
+    #[test]
+    fn accepts_its_own_signature() {
+        let signer = UrlSigner::new("key");
+        let expires = Utc::now().timestamp() + 60;
+        assert!(signer
+            .verify(UUID, "thumb", &signed(&signer, expires))
+            .is_ok());
+
+        let (path, expires) = signer.signed_path(UUID, "thumb", 60);
+        let signature = path.rsplit_once("signature=").unwrap().1;
+        let query = query(Some(expires), Some(signature));
+        assert!(signer.verify(UUID, "thumb", &query).is_ok());
+    }
+
+    #[test]
+    fn rejects_tampered_urls() {
+        let signer = UrlSigner::new("key");
+        let expires = Utc::now().timestamp() + 60;
+        let query = signed(&signer, expires);
+        let other = "1366591c-a5d8-487b-924c-b3314abb91f3";
+        assert!(signer.verify(other, "thumb", &query).is_err());
+        assert!(signer.verify(UUID, "original", &query).is_err());
+
+        let later = SignatureQuery {
+            expires: Some(expires + 1),
+            ..query
+        };
+        assert!(signer.verify(UUID, "thumb", &later).is_err());
+
+        let foreign = signed(&UrlSigner::new("other key"), expires);
+        assert!(signer.verify(UUID, "thumb", &foreign).is_err());
+    }
+
+    #[test]
+    fn rejects_expired_signatures() {
+        let signer = UrlSigner::new("key");
+        let expires = Utc::now().timestamp() - 1;
+        assert!(signer
+            .verify(UUID, "thumb", &signed(&signer, expires))
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_missing_or_malformed_signatures() {
+        let signer = UrlSigner::new("key");
+        let expires = Utc::now().timestamp() + 60;
+        let signature = signer.sign(UUID, "thumb", expires);
+        for query in [
+            query(None, None),
+            query(Some(expires), None),
+            query(None, Some(&signature)),
+            query(Some(expires), Some("")),
+            query(Some(expires), Some(&signature[1..])),
+            query(Some(expires), Some(&signature[..signature.len() - 2])),
+            query(Some(expires), Some(&format!("{}zz", &signature[2..]))),
+        ] {
+            assert!(signer.verify(UUID, "thumb", &query).is_err());
+        }
+    }
+}
@@ -0,0 +1,328 @@
+use rusqlite::{Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    account::ImagioAccount, edit::validate_category, transform::OutputFormat, variant::Variant,
+    ImagioError, ImagioState,
+};
+
+const CATEGORY_COLUMNS: &str =
+    "account_id, name, private, max_upload_bytes, allowed_variants, default_format";
+
+// Settings of a category. Categories are created on first upload with the
+// defaults, which leave everything to the server-wide options.
+#[derive(Debug, Clone, Serialize)]
+pub struct ImagioCategory {
+    #[serde(skip)]
+    pub(crate) account_id: i64,
+    pub(crate) name: String,
+    // Served only with a signed URL, like `--private-categories`
+    pub(crate) private: bool,
+    // Lower than `--max-upload-bytes` to take effect
+    pub(crate) max_upload_bytes: Option<u64>,
+    // Preset names, or `original`, that may be served; none means any
+    pub(crate) allowed_variants: Option<Vec<String>>,
+    // Output format of variants that do not set one
+    pub(crate) default_format: Option<OutputFormat>,
+}
+
+impl TryFrom<&Row<'_>> for ImagioCategory {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &Row<'_>) -> Result<Self, Self::Error> {
+        let allowed_variants: Option<String> = row.get(4)?;
+        let default_format: Option<String> = row.get(5)?;
+        Ok(ImagioCategory {
+            account_id: row.get(0)?,
+            name: row.get(1)?,
+            private: row.get(2)?,
+            max_upload_bytes: row.get(3)?,
+            allowed_variants: allowed_variants
+                .map(|names| names.split(',').map(str::to_string).collect()),
+            default_format: default_format.and_then(|format| format.parse().ok()),
+        })
+    }
+}
+
+impl ImagioCategory {
+    fn new(account_id: i64, name: &str) -> Self {
+        ImagioCategory {
+            account_id,
+            name: name.to_string(),
+            private: false,
+            max_upload_bytes: None,
+            allowed_variants: None,
+            default_format: None,
+        }
+    }
+
+    // Custom transformations are refused once variants are restricted
+    pub(crate) fn allows(&self, variant: &Variant) -> bool {
+        match (&self.allowed_variants, variant) {
+            (None, _) => true,
+            (Some(names), Variant::Original) => names.iter().any(|name| name == "original"),
+            (Some(names), Variant::Preset(name, _)) => names.contains(name),
+            (Some(_), Variant::Custom(_)) => false,
+        }
+    }
+
+    fn validate(&self, state: &ImagioState) -> Result<(), ImagioError> {
+        validate_category(&self.name)?;
+        for name in self.allowed_variants.iter().flatten() {
+            let preset = state.variants.resolve(name).ok();
+            if name != "original" && !matches!(preset, Some(Variant::Preset(..))) {
+                return Err(ImagioError::InvalidInput(format!(
+                    "unknown variant preset: {:?}",
+                    name
+                )));
+            }
+        }
+        if self.private && state.signer.is_none() {
+            return Err(ImagioError::InvalidInput(
+                "private categories require --signing-key".to_string(),
+            ));
+        }
+        if self.max_upload_bytes == Some(0) {
+            return Err(ImagioError::InvalidInput(
+                "max_upload_bytes must be positive".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Record a category on first use, with the default settings.
+pub(crate) fn ensure_category(
+    conn: &Connection,
+    account_id: i64,
+    name: &str,
+) -> Result<(), ImagioError> {
+    conn.execute(
+        "INSERT OR IGNORE INTO categories (account_id, name) VALUES (?, ?)",
+        (account_id, name),
+    )?;
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NewCategory {
+    name: String,
+    #[serde(default)]
+    private: bool,
+    max_upload_bytes: Option<u64>,
+    allowed_variants: Option<Vec<String>>,
+    default_format: Option<OutputFormat>,
+}
+
+// Changes to a category's settings; `null` resets a setting to the default.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CategoryPatch {
+    private: Option<bool>,
+    #[serde(default, with = "serde_with::rust::double_option")]
+    max_upload_bytes: Option<Option<u64>>,
+    #[serde(default, with = "serde_with::rust::double_option")]
+    allowed_variants: Option<Option<Vec<String>>>,
+    #[serde(default, with = "serde_with::rust::double_option")]
+    default_format: Option<Option<OutputFormat>>,
+}
+
+// A category with the number of images in it, outside the trash, and the
+// bytes their originals take up. Originals shared by several of its images
+// count once, those of older images of unknown size not at all.
+#[derive(Debug, Serialize)]
+pub struct CategoryUsage {
+    #[serde(flatten)]
+    category: ImagioCategory,
+    images: u64,
+    bytes: u64,
+}
+
+impl ImagioState {
+    // Whether images of the category are only served with a signed URL
+    pub(crate) fn is_private(&self, category: &ImagioCategory) -> bool {
+        category.private || self.private_categories.contains(&category.name)
+    }
+
+    // Settings of a category, the defaults when it was never used.
+    pub(crate) async fn category(
+        &self,
+        account_id: i64,
+        name: &str,
+    ) -> Result<ImagioCategory, ImagioError> {
+        let lock = self.db.read().await;
+        let conn = &lock.lock().await;
+        let category = conn
+            .query_row(
+                &format!(
+                    "SELECT {} FROM categories WHERE account_id = ? AND name = ?",
+                    CATEGORY_COLUMNS
+                ),
+                (account_id, name),
+                |row| ImagioCategory::try_from(row),
+            )
+            .optional()?;
+        Ok(category.unwrap_or_else(|| ImagioCategory::new(account_id, name)))
+    }
+
+    pub(crate) async fn list_categories(
+        &self,
+        account: &ImagioAccount,
+    ) -> Result<Vec<CategoryUsage>, ImagioError> {
+        let lock = self.db.read().await;
+        let conn = &lock.lock().await;
+        category_usage(conn, account.id, None)
+    }
+
+    pub(crate) async fn get_category(
+        &self,
+        account: &ImagioAccount,
+        name: &str,
+    ) -> Result<CategoryUsage, ImagioError> {
+        let lock = self.db.read().await;
+        let conn = &lock.lock().await;
+        category_usage(conn, account.id, Some(name))?
+            .pop()
+            .ok_or(ImagioError::NotFound)
+    }
+
+    pub(crate) async fn create_category(
+        &self,
+        account: &ImagioAccount,
+        new: NewCategory,
+    ) -> Result<CategoryUsage, ImagioError> {
+        let category = ImagioCategory {
+            account_id: account.id,
+            name: new.name,
+            private: new.private,
+            max_upload_bytes: new.max_upload_bytes,
+            allowed_variants: new.allowed_variants,
+            default_format: new.default_format,
+        };
+        category.validate(self)?;
+        {
+            let lock = self.db.write().await;
+            let conn = &lock.lock().await;
+            let exists = conn.query_row(
+                "SELECT EXISTS (SELECT 1 FROM categories WHERE account_id = ? AND name = ?) \
+                OR EXISTS (SELECT 1 FROM images WHERE account_id = ? AND category = ?)",
+                (account.id, &category.name, account.id, &category.name),
+                |row| row.get::<_, bool>(0),
+            )?;
+            if exists {
+                return Err(ImagioError::Conflict(format!(
+                    "category already exists: {}",
+                    category.name
+                )));
+            }
+            ensure_category(conn, account.id, &category.name)?;
+            save_settings(conn, &category)?;
+        }
+        tracing::info!("Category {:?} created", category.name);
+        self.get_category(account, &category.name).await
+    }
+
+    pub(crate) async fn update_category(
+        &self,
+        account: &ImagioAccount,
+        name: &str,
+        patch: CategoryPatch,
+    ) -> Result<CategoryUsage, ImagioError> {
+        let mut category = self.get_category(account, name).await?.category;
+        if let Some(private) = patch.private {
+            category.private = private;
+        }
+        if let Some(max_upload_bytes) = patch.max_upload_bytes {
+            category.max_upload_bytes = max_upload_bytes;
+        }
+        if let Some(allowed_variants) = patch.allowed_variants {
+            category.allowed_variants = allowed_variants;
+        }
+        if let Some(default_format) = patch.default_format {
+            category.default_format = default_format;
+        }
+        category.validate(self)?;
+        {
+            let lock = self.db.write().await;
+            let conn = &lock.lock().await;
+            save_settings(conn, &category)?;
+        }
+        self.get_category(account, name).await
+    }
+
+    // Only empty categories can be deleted, counting images in the trash.
+    pub(crate) async fn delete_category(
+        &self,
+        account: &ImagioAccount,
+        name: &str,
+    ) -> Result<(), ImagioError> {
+        let lock = self.db.write().await;
+        let conn = &lock.lock().await;
+        let used = conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM images WHERE account_id = ? AND category = ?)",
+            (account.id, name),
+            |row| row.get::<_, bool>(0),
+        )?;
+        if used {
+            return Err(ImagioError::Conflict(format!(
+                "category is not empty: {}",
+                name
+            )));
+        }
+        let deleted = conn.execute(
+            "DELETE FROM categories WHERE account_id = ? AND name = ?",
+            (account.id, name),
+        )?;
+        if deleted == 0 {
+            return Err(ImagioError::NotFound);
+        }
+        tracing::info!("Category {:?} deleted", name);
+        Ok(())
+    }
+}
+
+fn save_settings(conn: &Connection, category: &ImagioCategory) -> Result<(), ImagioError> {
+    conn.execute(
+        "UPDATE categories SET private = ?, max_upload_bytes = ?, allowed_variants = ?, \
+        default_format = ? WHERE account_id = ? AND name = ?",
+        (
+            category.private,
+            category.max_upload_bytes,
+            category
+                .allowed_variants
+                .as_ref()
+                .map(|names| names.join(",")),
+            category.default_format.map(|format| format.to_string()),
+            category.account_id,
+            &category.name,
+        ),
+    )?;
+    Ok(())
+}
+
+fn category_usage(
+    conn: &Connection,
+    account_id: i64,
+    name: Option<&str>,
+) -> Result<Vec<CategoryUsage>, ImagioError> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {}, \
+        (SELECT COUNT(*) FROM images WHERE images.account_id = categories.account_id \
+        AND images.category = categories.name AND images.deleted_at IS NULL), \
+        (SELECT COALESCE(SUM(size), 0) FROM (SELECT MAX(images.size) AS size FROM images \
+        WHERE images.account_id = categories.account_id \
+        AND images.category = categories.name AND images.deleted_at IS NULL \
+        GROUP BY COALESCE(images.blob_id, -images.id))) \
+        FROM categories WHERE account_id = ?1 AND (?2 IS NULL OR name = ?2) ORDER BY name",
+        CATEGORY_COLUMNS
+    ))?;
+    let rows = stmt.query_map((account_id, name), |row| {
+        Ok(CategoryUsage {
+            category: ImagioCategory::try_from(row)?,
+            images: row.get(6)?,
+            bytes: row.get(7)?,
+        })
+    })?;
+    Ok(rows.collect::<Result<Vec<_>, _>>()?)
+}
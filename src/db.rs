@@ -0,0 +1,70 @@
+use std::path::Path;
+
+use chrono::Utc;
+use rusqlite::Connection;
+
+use crate::ImagioError;
+
+// Ordered list of schema migrations, applied once each and recorded in the
+// `migrations` table. Append new entries here; never edit applied ones.
+const MIGRATIONS: &[(i64, &str)] = &[
+    (1, include_str!("../schema.sql")),
+    (2, include_str!("../migrations/0002_image_sha256.sql")),
+    (3, include_str!("../migrations/0003_tokens.sql")),
+    (4, include_str!("../migrations/0004_accounts.sql")),
+    (5, include_str!("../migrations/0005_image_metadata.sql")),
+    (6, include_str!("../migrations/0006_blobs.sql")),
+    (7, include_str!("../migrations/0007_image_edits.sql")),
+    (8, include_str!("../migrations/0008_deletions.sql")),
+    (9, include_str!("../migrations/0009_trash.sql")),
+    (10, include_str!("../migrations/0010_categories.sql")),
+    (11, include_str!("../migrations/0011_tags.sql")),
+    (12, include_str!("../migrations/0012_albums.sql")),
+];
+
+pub(crate) fn open(path: &str) -> Result<Connection, ImagioError> {
+    if let Some(parent) = Path::new(path).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut conn = Connection::open(path)?;
+    migrate(&mut conn)?;
+    Ok(conn)
+}
+
+pub(crate) fn migrate(conn: &mut Connection) -> Result<(), ImagioError> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS migrations (
+            version integer PRIMARY KEY,
+            apply_time datetime NOT NULL
+        );",
+    )?;
+    let current: i64 = conn
+        .query_row("SELECT MAX(version) FROM migrations", [], |row| {
+            row.get::<_, Option<i64>>(0)
+        })?
+        .unwrap_or(0);
+
+    for (version, sql) in MIGRATIONS.iter().filter(|(v, _)| *v > current) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(sql)?;
+        tx.execute(
+            "INSERT INTO migrations (version, apply_time) VALUES (?, ?)",
+            (version, Utc::now().to_string()),
+        )?;
+        tx.commit()?;
+        tracing::info!("Applied database migration {}", version);
+    }
+    Ok(())
+}
+
+pub(crate) fn init(path: &str, force: bool) -> Result<(), ImagioError> {
+    let db = Path::new(path);
+    if force && db.exists() {
+        let backup = format!("{}.{}.bak", path, Utc::now().format("%Y%m%d%H%M%S"));
+        std::fs::rename(db, &backup)?;
+        tracing::info!("Existing database moved to: {}", backup);
+    }
+    open(path)?;
+    tracing::info!("Database initialized at: {}", path);
+    Ok(())
+}
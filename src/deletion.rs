@@ -0,0 +1,81 @@
+use chrono::Utc;
+use rusqlite::Connection;
+
+use crate::{ImagioError, ImagioState};
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Storage {
+    Store,
+    Cache,
+}
+
+impl Storage {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Storage::Store => "store",
+            Storage::Cache => "cache",
+        }
+    }
+}
+
+// Schedule the removal of a stored object, or of a directory when the path
+// ends in `/`. Call it in the transaction that stops referring to the path,
+// so that a crash cannot leave objects behind that nothing knows about.
+pub(crate) fn schedule_deletion(
+    conn: &Connection,
+    storage: Storage,
+    path: &str,
+) -> Result<(), ImagioError> {
+    conn.execute(
+        "INSERT INTO deletions (storage, path, create_time) VALUES (?, ?, ?)",
+        (storage.as_str(), path, Utc::now().to_string()),
+    )?;
+    Ok(())
+}
+
+impl ImagioState {
+    // Carry out the scheduled deletions. Failed ones stay scheduled and are
+    // retried on the next run; callers have committed already, so failures
+    // are only logged.
+    pub(crate) async fn run_deletions(&self) {
+        if let Err(err) = self.try_run_deletions().await {
+            tracing::warn!("Running scheduled deletions failed: {}", err);
+        }
+    }
+
+    async fn try_run_deletions(&self) -> Result<(), ImagioError> {
+        let pending = {
+            let lock = self.db.read().await;
+            let conn = &lock.lock().await;
+            let mut stmt = conn.prepare("SELECT id, storage, path FROM deletions ORDER BY id")?;
+            let rows = stmt.query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })?;
+            rows.collect::<Result<Vec<_>, _>>()?
+        };
+
+        for (id, storage, path) in pending {
+            let op = match storage.as_str() {
+                "cache" => &self.storage.cache,
+                _ => &self.storage.store,
+            };
+            let deleted = match path.ends_with('/') {
+                true => op.remove_all(&path).await,
+                false => op.delete(&path).await,
+            };
+            if let Err(err) = deleted {
+                tracing::warn!("Deleting {:?} from {} failed: {}", path, storage, err);
+                continue;
+            }
+            tracing::info!("Deleted {:?} ({})", path, storage);
+            let lock = self.db.write().await;
+            let conn = &lock.lock().await;
+            conn.execute("DELETE FROM deletions WHERE id = ?", [id])?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,240 @@
+use axum::body::Bytes;
+use chrono::Utc;
+use futures_util::Stream;
+use serde::Deserialize;
+
+use crate::{
+    account::ImagioAccount,
+    app::{attach_blob, release_blob, sha256_hex},
+    category::ensure_category,
+    deletion::{schedule_deletion, Storage},
+    variant::Variant,
+    ImagioError, ImagioImage, ImagioState,
+};
+
+// Changes to an image. Absent fields are kept, `null` clears a text field.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImagePatch {
+    pub(crate) category: Option<String>,
+    #[serde(default, with = "serde_with::rust::double_option")]
+    pub(crate) title: Option<Option<String>>,
+    #[serde(default, with = "serde_with::rust::double_option")]
+    pub(crate) alt: Option<Option<String>>,
+}
+
+pub(crate) fn validate_category(category: &str) -> Result<(), ImagioError> {
+    if category.is_empty() || category.contains('/') || category == "." || category == ".." {
+        return Err(ImagioError::InvalidInput(format!(
+            "invalid category: {:?}",
+            category
+        )));
+    }
+    Ok(())
+}
+
+impl ImagioState {
+    // Apply a patch. Moving to another category also moves the original, as
+    // long as no other image shares it, and drops the rendered variants.
+    pub(crate) async fn update(
+        &self,
+        account: &ImagioAccount,
+        uuid: &str,
+        patch: ImagePatch,
+    ) -> Result<ImagioImage, ImagioError> {
+        let old = self.get(account, uuid).await?;
+        let mut image = old.clone();
+        if let Some(title) = patch.title {
+            image.title = title;
+        }
+        if let Some(alt) = patch.alt {
+            image.alt = alt;
+        }
+        let moved = patch.category.filter(|c| *c != old.category);
+        if let Some(category) = &moved {
+            validate_category(category)?;
+            image.category = category.clone();
+        }
+
+        // The original moves along when this image is its only user, under
+        // a fresh name: a pending deletion of an earlier original at this
+        // image's path could otherwise hit it. Images from before
+        // deduplication become the owner of a blob on the way. Whether the
+        // blob is still unshared is checked again once the write lock is
+        // held; if not, the copy is dropped and the original stays put.
+        let from = old.filename(&Variant::Original);
+        let unshared = match (&moved, old.blob_id) {
+            (None, _) => false,
+            (Some(_), None) => {
+                if image.sha256.is_none() {
+                    let buf = self.storage.store.read(&from).await?;
+                    image.sha256 = Some(sha256_hex(&buf.to_bytes()));
+                }
+                true
+            }
+            (Some(_), Some(blob_id)) => {
+                let lock = self.db.read().await;
+                let conn = &lock.lock().await;
+                let refs: i64 =
+                    conn.query_row("SELECT refs FROM blobs WHERE id = ?", [blob_id], |row| {
+                        row.get(0)
+                    })?;
+                refs == 1
+            }
+        };
+        let relocate = unshared.then(|| {
+            ImagioImage {
+                uuid: uuid::Uuid::new_v4().to_string(),
+                blob: None,
+                ..image.clone()
+            }
+            .filename(&Variant::Original)
+        });
+        if let Some(to) = &relocate {
+            self.storage.store.copy(&from, to).await?;
+            if old.blob_id.is_some() {
+                image.blob = Some(to.clone());
+            }
+        }
+
+        let updated = async {
+            let lock = self.db.write().await;
+            let conn = &mut lock.lock().await;
+            let tx = conn.transaction()?;
+            ensure_category(&tx, image.account_id, &image.category)?;
+            let mut relocated = relocate.is_some();
+            match (&relocate, old.blob_id) {
+                (Some(to), Some(blob_id)) => {
+                    let refs: i64 =
+                        tx.query_row("SELECT refs FROM blobs WHERE id = ?", [blob_id], |row| {
+                            row.get(0)
+                        })?;
+                    if refs == 1 {
+                        tx.execute("UPDATE blobs SET path = ? WHERE id = ?", (to, blob_id))?;
+                    } else {
+                        relocated = false;
+                        image.blob = old.blob.clone();
+                        schedule_deletion(&tx, Storage::Store, to)?;
+                    }
+                }
+                (Some(to), None) => {
+                    if attach_blob(&tx, &mut image, to)? {
+                        schedule_deletion(&tx, Storage::Store, to)?;
+                    }
+                }
+                (None, _) => {}
+            }
+            tx.execute(
+                "UPDATE images SET category = ?, title = ?, alt = ?, sha256 = ?, blob_id = ? \
+                WHERE uuid = ?",
+                (
+                    &image.category,
+                    &image.title,
+                    &image.alt,
+                    &image.sha256,
+                    &image.blob_id,
+                    &image.uuid,
+                ),
+            )?;
+            if relocated {
+                schedule_deletion(&tx, Storage::Store, &from)?;
+            }
+            if moved.is_some() {
+                schedule_deletion(&tx, Storage::Cache, &old.cache_dir())?;
+            }
+            tx.commit()?;
+            Ok::<_, ImagioError>(())
+        }
+        .await;
+        if let Err(err) = updated {
+            if let Some(to) = &relocate {
+                self.storage.store.delete(to).await.ok();
+            }
+            return Err(err);
+        }
+
+        if let Some(category) = &moved {
+            tracing::info!("Image {} moved to {:?}", image.uuid, category);
+            self.run_deletions().await;
+        }
+        Ok(image)
+    }
+
+    // Replace the content of an image, keeping its UUID. The new original is
+    // deduplicated like an upload and the rendered variants are dropped.
+    pub(crate) async fn replace<S, E>(
+        &self,
+        account: &ImagioAccount,
+        uuid: &str,
+        original_filename: Option<String>,
+        stream: S,
+    ) -> Result<ImagioImage, ImagioError>
+    where
+        S: Stream<Item = Result<Bytes, E>>,
+        ImagioError: From<E>,
+    {
+        let old = self.get(account, uuid).await?;
+        let fresh = self.store_original(account, &old.category, stream).await?;
+        let path = fresh.filename(&Variant::Original);
+        if fresh.sha256 == old.sha256 {
+            self.storage.store.delete(&path).await?;
+            return Ok(old);
+        }
+
+        let mut image = ImagioImage {
+            mime: fresh.mime,
+            sha256: fresh.sha256,
+            size: fresh.size,
+            metadata: fresh.metadata,
+            original_filename: original_filename.or(old.original_filename.clone()),
+            update_time: Some(Utc::now()),
+            ..old.clone()
+        };
+        let replaced = async {
+            let lock = self.db.write().await;
+            let conn = &mut lock.lock().await;
+            let tx = conn.transaction()?;
+            let unreferenced = release_blob(&tx, &old)?;
+            if attach_blob(&tx, &mut image, &path)? {
+                schedule_deletion(&tx, Storage::Store, &path)?;
+            }
+            if let Some(filename) = unreferenced {
+                schedule_deletion(&tx, Storage::Store, &filename)?;
+            }
+            schedule_deletion(&tx, Storage::Cache, &old.cache_dir())?;
+            let metadata = &image.metadata;
+            tx.execute(
+                "UPDATE images SET mime = ?, sha256 = ?, size = ?, original_filename = ?, \
+                width = ?, height = ?, camera = ?, taken_at = ?, orientation = ?, latitude = ?, \
+                longitude = ?, blob_id = ?, update_time = ? WHERE uuid = ?",
+                rusqlite::params![
+                    &image.mime.to_string(),
+                    &image.sha256,
+                    &image.size,
+                    &image.original_filename,
+                    &metadata.width,
+                    &metadata.height,
+                    &metadata.camera,
+                    &metadata.taken_at,
+                    &metadata.orientation,
+                    &metadata.latitude,
+                    &metadata.longitude,
+                    &image.blob_id,
+                    &image.modified().to_string(),
+                    &image.uuid,
+                ],
+            )?;
+            tx.commit()?;
+            Ok::<_, ImagioError>(())
+        }
+        .await;
+        if let Err(err) = replaced {
+            self.storage.store.delete(&path).await.ok();
+            return Err(err);
+        }
+
+        tracing::info!("Image {} replaced", image.uuid);
+        self.run_deletions().await;
+        Ok(image)
+    }
+}
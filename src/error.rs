@@ -1,39 +1,207 @@
-use axum::{body::Body, http::StatusCode};
+use axum::{
+    extract::Request,
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
 use thiserror::Error;
+use tracing::Instrument;
+
+use crate::auth::Scope;
 
 #[derive(Error, Debug)]
 pub enum ImagioError {
-    #[error("Not found")]
+    #[error("Not Found")]
     NotFound,
+    #[error("Unauthorized")]
+    Unauthorized,
+    #[error("Forbidden: missing {0} scope")]
+    Forbidden(Scope),
+    #[error("Missing, invalid or expired signature")]
+    InvalidSignature,
+    #[error("URL signing is not configured")]
+    SigningDisabled,
+    #[error("Invalid variant: {0}")]
+    InvalidVariant(String),
+    #[error("Invalid URL: {0}")]
+    InvalidUrl(String),
+    #[error("Fetch Error: {0}")]
+    FetchError(String),
+    #[error("Larger than {0} bytes")]
+    TooLarge(u64),
+    #[error("Image of {0}x{1} pixels is too large")]
+    DimensionsTooLarge(u32, u32),
+    #[error("Unsupported format: {0}")]
+    UnsupportedFormat(String),
+    #[error("Invalid image: {0}")]
+    InvalidImage(String),
+    #[error("Invalid input: {0}")]
+    InvalidInput(String),
+    #[error("More than {0} files in one request")]
+    TooManyFiles(usize),
+    #[error("Conflict: {0}")]
+    Conflict(String),
+    #[error("Method Not Allowed")]
+    MethodNotAllowed,
+    #[error("Config Error: {0}")]
+    ConfigError(String),
     #[error("Database Error: {0}")]
     DatabaseError(#[from] rusqlite::Error),
     #[error("Io Error: {0}")]
     IoError(#[from] std::io::Error),
+    #[error("Time Error: {0}")]
+    TimeError(#[from] chrono::ParseError),
     #[error("Mime Guess Error: {0}")]
     MimeError(#[from] mime_guess::mime::FromStrError),
     #[error("Multipart Error: {0}")]
     MultipartError(#[from] axum::extract::multipart::MultipartError),
     #[error("Image Error: {0}")]
     ImageError(#[from] image::ImageError),
+    #[error("Resize Error: {0}")]
+    ResizeError(#[from] fast_image_resize::ResizeError),
     #[error("Opendal Error: {0}")]
-    OpendalError(#[from] opendal::Error),
+    OpendalError(Box<opendal::Error>),
+}
+
+impl From<opendal::Error> for ImagioError {
+    fn from(err: opendal::Error) -> Self {
+        ImagioError::OpendalError(Box::new(err))
+    }
+}
+
+impl From<reqwest::Error> for ImagioError {
+    fn from(err: reqwest::Error) -> Self {
+        ImagioError::FetchError(err.to_string())
+    }
 }
 
-impl axum::response::IntoResponse for ImagioError {
-    fn into_response(self) -> axum::http::Response<Body> {
+impl ImagioError {
+    // HTTP status and stable, machine-readable code of the error
+    pub(crate) fn status(&self) -> (StatusCode, &'static str) {
         use ImagioError::*;
-        tracing::error!("{:?}", self);
-        let (status, body) = match self {
-            NotFound => (StatusCode::NOT_FOUND, "Not found".to_string()),
-            MultipartError(_) => (StatusCode::BAD_REQUEST, "Bad request".to_string()),
-            DatabaseError(_) | IoError(_) | MimeError(_) | ImageError(_) | OpendalError(_) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Internal server error".to_string(),
-            ),
+        match self {
+            NotFound => (StatusCode::NOT_FOUND, "not_found"),
+            Unauthorized => (StatusCode::UNAUTHORIZED, "unauthorized"),
+            Forbidden(_) => (StatusCode::FORBIDDEN, "forbidden"),
+            InvalidSignature => (StatusCode::FORBIDDEN, "invalid_signature"),
+            SigningDisabled => (StatusCode::NOT_IMPLEMENTED, "signing_disabled"),
+            InvalidVariant(_) => (StatusCode::BAD_REQUEST, "invalid_variant"),
+            InvalidUrl(_) => (StatusCode::BAD_REQUEST, "invalid_url"),
+            InvalidImage(_) => (StatusCode::BAD_REQUEST, "invalid_image"),
+            InvalidInput(_) => (StatusCode::BAD_REQUEST, "invalid_input"),
+            Conflict(_) => (StatusCode::CONFLICT, "conflict"),
+            MethodNotAllowed => (StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed"),
+            TooManyFiles(_) => (StatusCode::BAD_REQUEST, "too_many_files"),
+            // Includes bodies over the request size limit
+            MultipartError(err) => (err.status(), "invalid_multipart"),
+            TooLarge(_) => (StatusCode::PAYLOAD_TOO_LARGE, "too_large"),
+            DimensionsTooLarge(..) => (StatusCode::PAYLOAD_TOO_LARGE, "dimensions_too_large"),
+            UnsupportedFormat(_) => (StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_format"),
+            FetchError(_) => (StatusCode::BAD_GATEWAY, "fetch_failed"),
+            ImageError(image::ImageError::Decoding(_) | image::ImageError::Unsupported(_)) => {
+                (StatusCode::BAD_REQUEST, "decode_failed")
+            }
+            ImageError(image::ImageError::Limits(_)) => {
+                (StatusCode::PAYLOAD_TOO_LARGE, "dimensions_too_large")
+            }
+            OpendalError(err) if err.kind() == opendal::ErrorKind::NotFound => {
+                (StatusCode::NOT_FOUND, "not_found")
+            }
+            DatabaseError(rusqlite::Error::QueryReturnedNoRows) => {
+                (StatusCode::NOT_FOUND, "not_found")
+            }
+            ConfigError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "config_error"),
+            DatabaseError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "database_error"),
+            OpendalError(_) | IoError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "storage_error"),
+            TimeError(_) | MimeError(_) | ImageError(_) | ResizeError(_) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "internal_error")
+            }
+        }
+    }
+
+    // Description for clients. Server errors, and storage or database errors
+    // that would reveal paths and queries, are not explained to them.
+    pub(crate) fn message(&self) -> String {
+        use ImagioError::*;
+        let (status, _) = self.status();
+        match self {
+            OpendalError(_) | DatabaseError(_) | IoError(_) => {}
+            _ if !status.is_server_error() => return self.to_string(),
+            _ => {}
+        }
+        status.canonical_reason().unwrap_or("Error").to_string()
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    message: String,
+    request_id: Option<String>,
+}
+
+impl IntoResponse for ImagioError {
+    fn into_response(self) -> Response {
+        let (status, code) = self.status();
+        if status.is_server_error() {
+            tracing::error!("{:?}", self);
+        } else {
+            tracing::debug!("{:?}", self);
+        }
+        let body = ErrorBody {
+            code,
+            message: self.message(),
+            request_id: REQUEST_ID.try_with(String::clone).ok(),
         };
-        axum::http::Response::builder()
-            .status(status)
-            .body(Body::from(body))
-            .unwrap()
+        let mut response = (status, Json(body)).into_response();
+        if status == StatusCode::UNAUTHORIZED {
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        }
+        response
+    }
+}
+
+pub(crate) const REQUEST_ID_HEADER: &str = "x-request-id";
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+// Tag every request with an id, taken from the client's `X-Request-Id` when
+// it sent a sensible one, and echo it in the response header and error bodies.
+pub(crate) async fn request_id(request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let header = HeaderValue::from_str(&id).unwrap();
+
+    let span = tracing::info_span!("request", id = %id);
+    let mut response = REQUEST_ID
+        .scope(id, next.run(request))
+        .instrument(span)
+        .await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, header);
+    response
+}
+
+// The router answers a known path with an unrouted method by an empty `405`;
+// give it the error body, keeping the `Allow` header.
+pub(crate) async fn method_not_allowed(response: Response) -> Response {
+    if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+        return response;
+    }
+    let allow = response.headers().get(header::ALLOW).cloned();
+    let mut response = ImagioError::MethodNotAllowed.into_response();
+    if let Some(allow) = allow {
+        response.headers_mut().insert(header::ALLOW, allow);
     }
+    response
 }
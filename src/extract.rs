@@ -0,0 +1,132 @@
+use std::ops::{Deref, DerefMut};
+
+use axum::{
+    async_trait,
+    extract::{
+        multipart::MultipartRejection,
+        rejection::{JsonRejection, PathRejection, QueryRejection},
+        FromRequest, FromRequestParts, Request,
+    },
+    http::request::Parts,
+    response::{IntoResponse, Response},
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::ImagioError;
+
+// Stand-ins for axum's extractors that reject requests with the JSON error
+// body, like every other error, instead of a plain-text one.
+
+impl From<JsonRejection> for ImagioError {
+    fn from(rejection: JsonRejection) -> Self {
+        ImagioError::InvalidInput(rejection.body_text())
+    }
+}
+
+impl From<PathRejection> for ImagioError {
+    fn from(rejection: PathRejection) -> Self {
+        ImagioError::InvalidInput(rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for ImagioError {
+    fn from(rejection: QueryRejection) -> Self {
+        ImagioError::InvalidInput(rejection.body_text())
+    }
+}
+
+impl From<MultipartRejection> for ImagioError {
+    fn from(rejection: MultipartRejection) -> Self {
+        ImagioError::InvalidInput(rejection.body_text())
+    }
+}
+
+// Also a response, so that handlers use a single `Json`
+#[derive(Debug)]
+pub struct Json<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Json<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ImagioError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::Json(value) = axum::Json::<T>::from_request(req, state).await?;
+        Ok(Json(value))
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+#[derive(Debug)]
+pub struct Path<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Path<T>
+where
+    T: DeserializeOwned + Send,
+    S: Send + Sync,
+{
+    type Rejection = ImagioError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::extract::Path(value) =
+            axum::extract::Path::<T>::from_request_parts(parts, state).await?;
+        Ok(Path(value))
+    }
+}
+
+#[derive(Debug)]
+pub struct Query<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Query<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ImagioError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::extract::Query(value) =
+            axum::extract::Query::<T>::from_request_parts(parts, state).await?;
+        Ok(Query(value))
+    }
+}
+
+pub struct Multipart(axum::extract::Multipart);
+
+#[async_trait]
+impl<S> FromRequest<S> for Multipart
+where
+    S: Send + Sync,
+{
+    type Rejection = ImagioError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Multipart(
+            axum::extract::Multipart::from_request(req, state).await?,
+        ))
+    }
+}
+
+impl Deref for Multipart {
+    type Target = axum::extract::Multipart;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for Multipart {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
@@ -0,0 +1,279 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use axum::body::Bytes;
+use futures_util::{Stream, StreamExt};
+use reqwest::{header, redirect, Client};
+use url::Url;
+
+use crate::ImagioError;
+
+const MAX_REDIRECTS: usize = 5;
+
+// Downloads images on behalf of clients. Requests only go to public
+// addresses, unless the host is explicitly allowed, and never to denied
+// hosts; redirects are followed by hand so that every hop is checked.
+#[derive(Debug, Clone)]
+pub struct RemoteFetcher {
+    max_bytes: u64,
+    timeout: Duration,
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+impl RemoteFetcher {
+    pub(crate) fn new(
+        max_bytes: u64,
+        timeout: Duration,
+        allow: &[String],
+        deny: &[String],
+    ) -> Self {
+        let lowercase = |hosts: &[String]| hosts.iter().map(|h| h.to_ascii_lowercase()).collect();
+        RemoteFetcher {
+            max_bytes,
+            timeout,
+            allow: lowercase(allow),
+            deny: lowercase(deny),
+        }
+    }
+
+    // Returns the file name from the final URL and the size-limited body.
+    pub(crate) async fn fetch(
+        &self,
+        url: &str,
+    ) -> Result<
+        (
+            Option<String>,
+            impl Stream<Item = Result<Bytes, ImagioError>>,
+        ),
+        ImagioError,
+    > {
+        let mut url = Url::parse(url).map_err(|e| ImagioError::InvalidUrl(e.to_string()))?;
+        let mut redirects = 0;
+        let response = loop {
+            let client = self.client(&url).await?;
+            let response = client.get(url.clone()).send().await?;
+            if !response.status().is_redirection() {
+                break response.error_for_status()?;
+            }
+            redirects += 1;
+            let location = response
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .filter(|_| redirects <= MAX_REDIRECTS)
+                .ok_or_else(|| ImagioError::FetchError(format!("bad redirect from {}", url)))?;
+            url = url
+                .join(location)
+                .map_err(|e| ImagioError::InvalidUrl(e.to_string()))?;
+        };
+
+        if response
+            .content_length()
+            .is_some_and(|n| n > self.max_bytes)
+        {
+            return Err(ImagioError::TooLarge(self.max_bytes));
+        }
+        let filename = url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .filter(|name| !name.is_empty())
+            .map(str::to_string);
+
+        let max_bytes = self.max_bytes;
+        let mut received = 0;
+        let body = response.bytes_stream().map(move |chunk| {
+            let chunk = chunk?;
+            received += chunk.len() as u64;
+            if received > max_bytes {
+                return Err(ImagioError::TooLarge(max_bytes));
+            }
+            Ok(chunk)
+        });
+        Ok((filename, body))
+    }
+
+    // A client pinned to the checked addresses of the URL's host, so that a
+    // second DNS lookup cannot point the request elsewhere.
+    async fn client(&self, url: &Url) -> Result<Client, ImagioError> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(ImagioError::InvalidUrl(format!(
+                "unsupported scheme: {}",
+                url.scheme()
+            )));
+        }
+        let host = url
+            .host_str()
+            .ok_or_else(|| ImagioError::InvalidUrl(format!("missing host: {}", url)))?
+            .to_ascii_lowercase();
+        if matches_any(&self.deny, &host) {
+            return Err(ImagioError::InvalidUrl(format!("host is denied: {}", host)));
+        }
+        let allowed = matches_any(&self.allow, &host);
+        if !self.allow.is_empty() && !allowed {
+            return Err(ImagioError::InvalidUrl(format!(
+                "host is not allowed: {}",
+                host
+            )));
+        }
+
+        let port = url.port_or_known_default().unwrap_or(80);
+        let lookup = host.trim_start_matches('[').trim_end_matches(']');
+        let addrs = tokio::net::lookup_host((lookup, port))
+            .await
+            .map_err(|e| ImagioError::FetchError(format!("{}: {}", host, e)))?
+            .collect::<Vec<SocketAddr>>();
+        if addrs.is_empty() || (!allowed && !addrs.iter().all(|a| is_public(a.ip()))) {
+            return Err(ImagioError::InvalidUrl(format!(
+                "host does not resolve to a public address: {}",
+                host
+            )));
+        }
+
+        let client = Client::builder()
+            .redirect(redirect::Policy::none())
+            .timeout(self.timeout)
+            .resolve_to_addrs(&host, &addrs)
+            .build()?;
+        Ok(client)
+    }
+}
+
+// Patterns are host names, or `*.example.com` for any subdomain.
+fn matches_any(patterns: &[String], host: &str) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| match pattern.strip_prefix("*.") {
+            Some(domain) => host
+                .strip_suffix(domain)
+                .is_some_and(|sub| sub.ends_with('.')),
+            None => pattern == host,
+        })
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                // Reserved (240.0.0.0/4), including broadcast
+                || a >= 240
+                // Shared address space (RFC 6598)
+                || (a == 100 && (64..128).contains(&b))
+                // IETF protocol assignments (192.0.0.0/24)
+                || (a == 192 && b == 0 && c == 0)
+                // Benchmarking (198.18.0.0/15)
+                || (a == 198 && (b & 0xfe) == 18))
+        }
+        IpAddr::V6(ip) => {
+            if ip.is_loopback() || ip.is_unspecified() {
+                return false;
+            }
+            if let Some(ip) = embedded_ipv4(ip) {
+                return is_public(IpAddr::V4(ip));
+            }
+            let [first, second, ..] = ip.segments();
+            !(ip.is_multicast()
+                // Unique local (fc00::/7) and link-local (fe80::/10)
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+                // Local-use NAT64 (64:ff9b:1::/48), Teredo (2001::/32) and
+                // documentation (2001:db8::/32), which hide or lack a target
+                || (first == 0x64 && second == 0xff9b)
+                || (first == 0x2001 && (second == 0 || second == 0xdb8)))
+        }
+    }
+}
+
+// The IPv4 address an IPv6 one reaches: mapped (::ffff:0:0/96), compatible
+// (::/96), NAT64 (64:ff9b::/96) or 6to4 (2002::/16).
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let s = ip.segments();
+    let from = |high: u16, low: u16| Ipv4Addr::from(((high as u32) << 16) | low as u32);
+    match s {
+        [0, 0, 0, 0, 0, 0xffff, ..] | [0, 0, 0, 0, 0, 0, ..] => Some(from(s[6], s[7])),
+        [0x64, 0xff9b, 0, 0, 0, 0, ..] => Some(from(s[6], s[7])),
+        [0x2002, ..] => Some(from(s[1], s[2])),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn public(ip: &str) -> bool {
+        is_public(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn public_addresses() {
+        for ip in [
+            "8.8.8.8",
+            "93.184.216.34",
+            "2606:4700::1111",
+            "::ffff:8.8.8.8",
+            "64:ff9b::808:808",
+            "2002:808:808::1",
+        ] {
+            assert!(public(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn private_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "100.64.0.1",
+            "192.0.0.8",
+            "198.18.0.1",
+            "198.19.255.255",
+            "224.0.0.1",
+            "239.255.255.250",
+            "240.0.0.1",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fc00::1",
+            "fe80::1",
+            "ff02::1",
+            "::ffff:127.0.0.1",
+            "::127.0.0.1",
+            "::10.0.0.1",
+            "64:ff9b::7f00:1",
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b:1::808:808",
+            "2002:7f00:1::",
+            "2002:c0a8:101::1",
+            "2001::1",
+            "2001:db8::1",
+        ] {
+            assert!(!public(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn host_patterns() {
+        let patterns = vec!["example.com".to_string(), "*.images.test".to_string()];
+        assert!(matches_any(&patterns, "example.com"));
+        assert!(!matches_any(&patterns, "www.example.com"));
+        assert!(matches_any(&patterns, "cdn.images.test"));
+        assert!(matches_any(&patterns, "a.b.images.test"));
+        assert!(!matches_any(&patterns, "images.test"));
+        assert!(!matches_any(&patterns, "evilimages.test"));
+        assert!(!matches_any(&[], "example.com"));
+    }
+}
@@ -1,7 +1,21 @@
+mod account;
+mod album;
 mod api;
 mod app;
+mod auth;
+mod category;
+mod db;
+mod deletion;
+mod edit;
 mod error;
+mod extract;
+mod fetch;
+mod metadata;
+mod query;
 mod server;
+mod tag;
+mod transform;
+mod trash;
 mod variant;
 
 use app::*;
@@ -14,19 +28,41 @@ use variant::generate;
 async fn main() -> Result<(), ImagioError> {
     let cli = ImagioCli::parse();
 
-    tracing_subscriber::fmt::fmt().init();
+    tracing_subscriber::fmt::fmt()
+        .with_writer(std::io::stderr)
+        .init();
 
-    match cli.command {
-        ImagioCommand::Init { .. } => {
+    match cli.command.clone() {
+        ImagioCommand::Init { force } => {
             tracing::info!("Initializing database");
+            db::init(&cli.db, force)?;
         }
-        ImagioCommand::Generate => {
+        ImagioCommand::Generate {
+            category,
+            variant,
+            concurrency,
+            skip_cached,
+        } => {
             tracing::info!("Generating variants");
-            generate()?;
+            let state = std::sync::Arc::new(ImagioState::new(cli)?);
+            generate(state, &category, &variant, concurrency, skip_cached).await?;
+        }
+        ImagioCommand::Account { command } => {
+            let conn = db::open(&cli.db)?;
+            account::ImagioAccount::adopt_legacy(&conn, &cli.account_id)?;
+            account::account_command(&conn, command)?;
+        }
+        ImagioCommand::Token { command } => {
+            let conn = db::open(&cli.db)?;
+            account::ImagioAccount::adopt_legacy(&conn, &cli.account_id)?;
+            auth::token_command(&conn, &cli.account_id, command)?;
         }
         ImagioCommand::Serve => {
             let state = ImagioState::new(cli)?;
             let async_state = std::sync::Arc::new(state);
+            // Finish deletions interrupted by a previous shutdown
+            async_state.run_deletions().await;
+            tokio::spawn(async_state.clone().purge_periodically());
             tracing::info!("Starting server at {}", async_state.bind);
             server(async_state).await?;
         }
@@ -0,0 +1,92 @@
+use std::io::Cursor;
+
+use exif::{Exif, In, Tag, Value};
+use serde::Serialize;
+
+// Facts read from the headers of an uploaded image. Everything is optional:
+// images uploaded before metadata was captured, or without EXIF, simply
+// lack them.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ImageMetadata {
+    pub(crate) width: Option<u32>,
+    pub(crate) height: Option<u32>,
+    pub(crate) camera: Option<String>,
+    pub(crate) taken_at: Option<String>,
+    pub(crate) orientation: Option<u32>,
+    pub(crate) latitude: Option<f64>,
+    pub(crate) longitude: Option<f64>,
+}
+
+impl ImageMetadata {
+    // Only the headers are parsed, so a prefix of the file usually suffices;
+    // missing dimensions tell the caller to retry with the whole file.
+    pub(crate) fn read(data: &[u8]) -> ImageMetadata {
+        // Nothing is decoded here, so oversized images need no limits yet
+        let (width, height) = image::io::Reader::new(Cursor::new(data))
+            .with_guessed_format()
+            .ok()
+            .and_then(|mut reader| {
+                reader.no_limits();
+                reader.into_dimensions().ok()
+            })
+            .unzip();
+        let mut metadata = ImageMetadata {
+            width,
+            height,
+            ..Default::default()
+        };
+        if let Ok(exif) = exif::Reader::new().read_from_container(&mut Cursor::new(data)) {
+            metadata.read_exif(&exif);
+        }
+        metadata
+    }
+
+    fn read_exif(&mut self, exif: &Exif) {
+        let ascii = |tag| match exif.get_field(tag, In::PRIMARY).map(|f| &f.value) {
+            Some(Value::Ascii(values)) => values
+                .first()
+                .map(|v| String::from_utf8_lossy(v).trim().to_string())
+                .filter(|v| !v.is_empty()),
+            _ => None,
+        };
+
+        self.camera = match (ascii(Tag::Make), ascii(Tag::Model)) {
+            // Models usually repeat the make already
+            (Some(make), Some(model)) if model.starts_with(&make) => Some(model),
+            (Some(make), Some(model)) => Some(format!("{} {}", make, model)),
+            (make, model) => make.or(model),
+        };
+        self.taken_at = ascii(Tag::DateTimeOriginal)
+            .or_else(|| ascii(Tag::DateTime))
+            .and_then(|time| exif::DateTime::from_ascii(time.as_bytes()).ok())
+            .map(|t| {
+                format!(
+                    "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+                    t.year, t.month, t.day, t.hour, t.minute, t.second
+                )
+            });
+        self.orientation = exif
+            .get_field(Tag::Orientation, In::PRIMARY)
+            .and_then(|field| field.value.get_uint(0));
+        self.latitude = coordinate(exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, "S");
+        self.longitude = coordinate(exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, "W");
+    }
+}
+
+// GPS coordinates are stored as degrees, minutes and seconds plus a
+// hemisphere reference; convert them to signed decimal degrees.
+fn coordinate(exif: &Exif, tag: Tag, reference: Tag, negative: &str) -> Option<f64> {
+    let Value::Rational(dms) = &exif.get_field(tag, In::PRIMARY)?.value else {
+        return None;
+    };
+    let degrees = dms
+        .iter()
+        .zip([1.0, 60.0, 3600.0])
+        .map(|(value, unit)| value.to_f64() / unit)
+        .sum::<f64>();
+    let sign = match &exif.get_field(reference, In::PRIMARY)?.value {
+        Value::Ascii(values) if values.first().is_some_and(|v| v == negative.as_bytes()) => -1.0,
+        _ => 1.0,
+    };
+    Some(sign * degrees).filter(|degrees| degrees.is_finite())
+}
@@ -0,0 +1,427 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use rusqlite::types::Value;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    account::ImagioAccount, app::SELECT_IMAGES, tag::normalize_tag, ImagioError, ImagioImage,
+    ImagioState,
+};
+
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 500;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortField {
+    #[default]
+    Created,
+    Size,
+    TakenAt,
+    Filename,
+}
+
+impl SortField {
+    // Missing values sort first, as the smallest
+    fn expr(&self) -> &'static str {
+        match self {
+            SortField::Created => "images.create_time",
+            SortField::Size => "COALESCE(images.size, -1)",
+            SortField::TakenAt => "COALESCE(images.taken_at, '')",
+            SortField::Filename => "COALESCE(images.original_filename, '')",
+        }
+    }
+}
+
+impl FromStr for SortField {
+    type Err = ImagioError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "created" => Ok(SortField::Created),
+            "size" => Ok(SortField::Size),
+            "taken_at" => Ok(SortField::TakenAt),
+            "filename" => Ok(SortField::Filename),
+            _ => Err(ImagioError::InvalidInput(format!("unknown sort: {}", s))),
+        }
+    }
+}
+
+// Filters, ordering and position of an image listing, from query
+// parameters. `category` and `mime` may be repeated to match any of the
+// values, `tag` to require all of them.
+#[derive(Debug, Default)]
+pub struct ImageQuery {
+    categories: Vec<String>,
+    mimes: Vec<String>,
+    tags: Vec<String>,
+    search: Option<String>,
+    created_after: Option<DateTime<Utc>>,
+    created_before: Option<DateTime<Utc>>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    sort: SortField,
+    descending: bool,
+    limit: Option<usize>,
+    cursor: Option<Cursor>,
+}
+
+impl TryFrom<Vec<(String, String)>> for ImageQuery {
+    type Error = ImagioError;
+
+    fn try_from(params: Vec<(String, String)>) -> Result<Self, Self::Error> {
+        let mut query = ImageQuery {
+            descending: true,
+            ..Default::default()
+        };
+        for (key, value) in params {
+            let invalid = || ImagioError::InvalidInput(format!("invalid {}: {:?}", key, value));
+            match key.as_str() {
+                "category" => query.categories.push(value),
+                "mime" => query.mimes.push(value),
+                "tag" => query.tags.push(normalize_tag(&value)?),
+                "q" => query.search = Some(value),
+                "created_after" => {
+                    query.created_after = Some(parse_time(&value).ok_or_else(invalid)?)
+                }
+                "created_before" => {
+                    query.created_before = Some(parse_time(&value).ok_or_else(invalid)?)
+                }
+                "min_size" => query.min_size = Some(value.parse().map_err(|_| invalid())?),
+                "max_size" => query.max_size = Some(value.parse().map_err(|_| invalid())?),
+                "sort" => query.sort = value.parse()?,
+                "order" => {
+                    query.descending = match value.as_str() {
+                        "asc" => false,
+                        "desc" => true,
+                        _ => return Err(invalid()),
+                    }
+                }
+                "limit" => {
+                    let limit = value.parse().map_err(|_| invalid())?;
+                    if !(1..=MAX_LIMIT).contains(&limit) {
+                        return Err(invalid());
+                    }
+                    query.limit = Some(limit);
+                }
+                "cursor" => query.cursor = Some(Cursor::decode(&value).ok_or_else(invalid)?),
+                _ => {
+                    return Err(ImagioError::InvalidInput(format!(
+                        "unknown parameter: {}",
+                        key
+                    )))
+                }
+            }
+        }
+        if let Some(cursor) = &query.cursor {
+            if cursor.sort != query.sort || cursor.descending != query.descending {
+                return Err(ImagioError::InvalidInput(
+                    "cursor belongs to another sort order".to_string(),
+                ));
+            }
+        }
+        Ok(query)
+    }
+}
+
+impl ImageQuery {
+    // Whether `q` has words to search for
+    pub(crate) fn has_search(&self) -> bool {
+        self.search.as_deref().and_then(match_expr).is_some()
+    }
+}
+
+// Full-text query matching every word of the search, also as a prefix.
+// Words are quoted so that FTS5 syntax in them is taken literally.
+fn match_expr(search: &str) -> Option<String> {
+    let words = search
+        .split_whitespace()
+        .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>();
+    (!words.is_empty()).then(|| words.join(" "))
+}
+
+// An RFC 3339 time, or a date meaning its midnight in UTC.
+fn parse_time(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|t| t.with_timezone(&Utc))
+        .ok()
+        .or_else(|| {
+            let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+            Some(date.and_hms_opt(0, 0, 0)?.and_utc())
+        })
+}
+
+// Position after the last image of a page: its sort key and row id, which
+// stay put when images are added, unlike an offset.
+#[derive(Debug, Serialize, Deserialize)]
+struct Cursor {
+    sort: SortField,
+    descending: bool,
+    key: serde_json::Value,
+    id: i64,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        hex::encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    fn decode(value: &str) -> Option<Cursor> {
+        serde_json::from_slice(&hex::decode(value).ok()?).ok()
+    }
+
+    fn key(&self) -> Value {
+        match &self.key {
+            serde_json::Value::Number(n) => Value::Integer(n.as_i64().unwrap_or_default()),
+            serde_json::Value::String(s) => Value::Text(s.clone()),
+            _ => Value::Null,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImagePage {
+    pub(crate) images: Vec<ImagioImage>,
+    // Images matching the filters, across all pages
+    total: u64,
+    next_cursor: Option<String>,
+}
+
+impl ImagioState {
+    pub(crate) async fn query(
+        &self,
+        account: &ImagioAccount,
+        query: ImageQuery,
+    ) -> Result<ImagePage, ImagioError> {
+        let mut filter = "images.account_id = ? AND images.deleted_at IS NULL".to_string();
+        let mut params = vec![Value::Integer(account.id)];
+        let mut any_of = |filter: &mut String, column: &str, values: &[String]| {
+            if !values.is_empty() {
+                let marks = vec!["?"; values.len()].join(", ");
+                filter.push_str(&format!(" AND {} IN ({})", column, marks));
+                params.extend(values.iter().cloned().map(Value::Text));
+            }
+        };
+        any_of(&mut filter, "images.category", &query.categories);
+        any_of(&mut filter, "images.mime", &query.mimes);
+        for tag in &query.tags {
+            filter.push_str(
+                " AND images.id IN (SELECT image_tags.image_id FROM image_tags \
+                JOIN tags ON tags.id = image_tags.tag_id WHERE tags.name = ?)",
+            );
+            params.push(Value::Text(tag.clone()));
+        }
+        if let Some(search) = query.search.as_deref().and_then(match_expr) {
+            filter.push_str(
+                " AND images.id IN (SELECT rowid FROM images_fts WHERE images_fts MATCH ?)",
+            );
+            params.push(Value::Text(search));
+        }
+        if let Some(after) = query.created_after {
+            filter.push_str(" AND images.create_time >= ?");
+            params.push(Value::Text(after.to_string()));
+        }
+        if let Some(before) = query.created_before {
+            filter.push_str(" AND images.create_time < ?");
+            params.push(Value::Text(before.to_string()));
+        }
+        if let Some(min_size) = query.min_size {
+            filter.push_str(" AND images.size >= ?");
+            params.push(Value::Integer(min_size as i64));
+        }
+        if let Some(max_size) = query.max_size {
+            filter.push_str(" AND images.size <= ?");
+            params.push(Value::Integer(max_size as i64));
+        }
+
+        let lock = self.db.read().await;
+        let conn = &lock.lock().await;
+        let total: u64 = conn.query_row(
+            &format!("SELECT COUNT(*) FROM images WHERE {}", filter),
+            rusqlite::params_from_iter(&params),
+            |row| row.get(0),
+        )?;
+
+        let expr = query.sort.expr();
+        let (compare, direction) = match query.descending {
+            true => ("<", "DESC"),
+            false => (">", "ASC"),
+        };
+        if let Some(cursor) = &query.cursor {
+            filter.push_str(&format!(" AND ({}, images.id) {} (?, ?)", expr, compare));
+            params.extend([cursor.key(), Value::Integer(cursor.id)]);
+        }
+        let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+        let mut stmt = conn.prepare(&format!(
+            "{} WHERE {} ORDER BY {} {}, images.id {} LIMIT {}",
+            SELECT_IMAGES, filter, expr, direction, direction, limit
+        ))?;
+        let mut rows = stmt.query(rusqlite::params_from_iter(&params))?;
+
+        let mut images = Vec::new();
+        while let Some(row) = rows.next()? {
+            let image = ImagioImage::try_from(row)?;
+            images.push(image);
+        }
+
+        // A full page may have more after it
+        let last = images.last().filter(|_| images.len() == limit);
+        let next_cursor = match last {
+            Some(last) => {
+                let (key, id) = conn.query_row(
+                    &format!(
+                        "SELECT {}, images.id FROM images WHERE images.uuid = ?",
+                        expr
+                    ),
+                    [&last.uuid],
+                    |row| Ok((row.get::<_, Value>(0)?, row.get::<_, i64>(1)?)),
+                )?;
+                let key = match key {
+                    Value::Integer(n) => n.into(),
+                    Value::Text(s) => s.into(),
+                    _ => serde_json::Value::Null,
+                };
+                let cursor = Cursor {
+                    sort: query.sort,
+                    descending: query.descending,
+                    key,
+                    id,
+                };
+                Some(cursor.encode())
+            }
+            None => None,
+        };
+
+        Ok(ImagePage {
+            images,
+            total,
+            next_cursor,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(params: &[(&str, &str)]) -> Result<ImageQuery, ImagioError> {
+        let params = params
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect::<Vec<_>>();
+        ImageQuery::try_from(params)
+    }
+
+    fn cursor(sort: SortField, descending: bool) -> String {
+        Cursor {
+            sort,
+            descending,
+            key: "2024-05-01".into(),
+            id: 42,
+        }
+        .encode()
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        let encoded = cursor(SortField::TakenAt, false);
+        let decoded = Cursor::decode(&encoded).unwrap();
+        assert_eq!(decoded.sort, SortField::TakenAt);
+        assert!(!decoded.descending);
+        assert_eq!(decoded.key(), Value::Text("2024-05-01".to_string()));
+        assert_eq!(decoded.id, 42);
+
+        let numeric = Cursor {
+            key: 1234.into(),
+            ..decoded
+        };
+        let decoded = Cursor::decode(&numeric.encode()).unwrap();
+        assert_eq!(decoded.key(), Value::Integer(1234));
+
+        assert!(Cursor::decode("not hex").is_none());
+        assert!(Cursor::decode(&hex::encode("{}")).is_none());
+    }
+
+    #[test]
+    fn cursors_belong_to_their_sort_order() {
+        let newest = cursor(SortField::Created, true);
+        assert!(query(&[("cursor", &newest)]).is_ok());
+        assert!(query(&[("cursor", &newest), ("order", "asc")]).is_err());
+        assert!(query(&[("cursor", &newest), ("sort", "size")]).is_err());
+
+        let by_size = cursor(SortField::Size, false);
+        assert!(query(&[("sort", "size"), ("order", "asc"), ("cursor", &by_size)]).is_ok());
+        assert!(query(&[("cursor", "zz")]).is_err());
+    }
+
+    #[test]
+    fn limits_are_bounded() {
+        assert_eq!(query(&[]).unwrap().limit, None);
+        assert_eq!(query(&[("limit", "1")]).unwrap().limit, Some(1));
+        assert_eq!(query(&[("limit", "500")]).unwrap().limit, Some(MAX_LIMIT));
+        for limit in ["0", "501", "-1", "ten"] {
+            assert!(query(&[("limit", limit)]).is_err(), "{}", limit);
+        }
+    }
+
+    #[test]
+    fn parses_times() {
+        let midnight: DateTime<Utc> = "2024-05-01T00:00:00Z".parse().unwrap();
+        assert_eq!(parse_time("2024-05-01"), Some(midnight));
+        assert_eq!(parse_time("2024-05-01T02:00:00+02:00"), Some(midnight),);
+        for value in [
+            "2024-13-01",
+            "2024-02-30",
+            "yesterday",
+            "",
+            "2024-05-01T25:00:00Z",
+        ] {
+            assert_eq!(parse_time(value), None, "{}", value);
+            assert!(query(&[("created_after", value)]).is_err());
+        }
+    }
+
+    #[test]
+    fn builds_match_expressions() {
+        assert_eq!(match_expr("beach"), Some("\"beach\"*".to_string()));
+        assert_eq!(
+            match_expr("  sunny   beach "),
+            Some("\"sunny\"* \"beach\"*".to_string())
+        );
+        // FTS5 syntax is taken literally
+        assert_eq!(
+            match_expr("a\"b OR c*"),
+            Some("\"a\"\"b\"* \"OR\"* \"c*\"*".to_string())
+        );
+        assert_eq!(match_expr(""), None);
+        assert_eq!(match_expr(" \t\n"), None);
+
+        assert!(query(&[("q", "beach")]).unwrap().has_search());
+        assert!(!query(&[("q", "  ")]).unwrap().has_search());
+        assert!(!query(&[]).unwrap().has_search());
+    }
+
+    #[test]
+    fn rejects_unknown_or_invalid_parameters() {
+        assert!(query(&[("colour", "red")]).is_err());
+        assert!(query(&[("sort", "random")]).is_err());
+        assert!(query(&[("order", "up")]).is_err());
+        assert!(query(&[("min_size", "big")]).is_err());
+        assert!(query(&[("tag", "a,b")]).is_err());
+
+        let query = query(&[
+            ("category", "cats"),
+            ("category", "dogs"),
+            ("tag", "Beach"),
+            ("sort", "filename"),
+            ("order", "asc"),
+        ])
+        .unwrap();
+        assert_eq!(query.categories, vec!["cats", "dogs"]);
+        assert_eq!(query.tags, vec!["beach"]);
+        assert_eq!(query.sort, SortField::Filename);
+        assert!(!query.descending);
+    }
+}
@@ -1,36 +1,346 @@
-use std::sync::Arc;
+use std::{ops::Range, sync::Arc};
 
 use axum::{
     body::Body,
-    extract::{Path, State},
+    extract::State,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
     routing::get,
     Router,
 };
 
-use crate::{api::*, variant::Variant, ImagioError, ImagioState};
+use chrono::{DateTime, Utc};
+
+use crate::{
+    api::*,
+    app::sha256_hex,
+    auth::SignatureQuery,
+    error::{method_not_allowed, request_id},
+    extract::{Path, Query},
+    variant::Variant,
+    ImagioError, ImagioImage, ImagioState,
+};
 
 pub async fn uuid_handler(
-    Path((uuid, variant)): Path<(String, Variant)>,
+    Path((uuid, variant_name)): Path<(String, String)>,
+    Query(signature): Query<SignatureQuery>,
     State(state): State<Arc<ImagioState>>,
-) -> axum::response::Result<Body, ImagioError> {
+    headers: HeaderMap,
+) -> axum::response::Result<Response, ImagioError> {
     tracing::info!("Requesting image with uuid: {}", uuid);
-    let image = state.get(&uuid).await?;
+    let variant = state.variants.resolve(&variant_name)?;
+    let image = state.find(&uuid).await?;
+    let category = state.category(image.account_id, &image.category).await?;
+    // Before anything else about the image is revealed
+    let cache_control = if state.is_private(&category) {
+        let signer = state.signer.as_ref().ok_or(ImagioError::SigningDisabled)?;
+        signer.verify(&uuid, &variant_name, &signature)?;
+        // Signed responses must not outlive their signature in shared caches
+        let max_age = signature.expires.unwrap_or_default() - Utc::now().timestamp();
+        HeaderValue::from_str(&format!("private, max-age={}", max_age)).unwrap()
+    } else {
+        state.cache_control.clone()
+    };
+    if !category.allows(&variant) {
+        return Err(ImagioError::InvalidVariant(format!(
+            "{} is not served for this category",
+            variant_name
+        )));
+    }
+
+    let negotiable = variant.negotiable() && !state.negotiate.is_empty();
+    let accept = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let mut variant = variant.negotiate(accept, &state.negotiate);
+    if let Some(format) = category.default_format {
+        variant = variant.with_format(format);
+    }
+
+    if let Variant::Original = variant {
+        return original_response(&state, &image, &headers, cache_control).await;
+    }
+
+    let content_type = variant.format(&image).mime();
     let body = state.variant(&image, variant).await?;
-    Ok(Body::from(body))
+    let etag = format!("\"{}\"", sha256_hex(&body));
+    let mut response_headers = cache_headers(&image, &etag, cache_control);
+    if negotiable {
+        response_headers.insert(header::VARY, HeaderValue::from_static("Accept"));
+    }
+
+    if not_modified(&headers, &etag, &image.modified()) {
+        return Ok((StatusCode::NOT_MODIFIED, response_headers).into_response());
+    }
+    response_headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    Ok((response_headers, Body::from(body)).into_response())
+}
+
+async fn original_response(
+    state: &ImagioState,
+    image: &ImagioImage,
+    headers: &HeaderMap,
+    cache_control: HeaderValue,
+) -> axum::response::Result<Response, ImagioError> {
+    // Images without a recorded hash are read in full once to compute it
+    let (sha256, full) = match &image.sha256 {
+        Some(sha256) => (sha256.clone(), None),
+        None => {
+            let body = state.variant(image, Variant::Original).await?;
+            (state.original_sha256(image, &body).await?, Some(body))
+        }
+    };
+    let etag = format!("\"{}\"", sha256);
+    let mut response_headers = cache_headers(image, &etag, cache_control);
+    response_headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+
+    if not_modified(headers, &etag, &image.modified()) {
+        return Ok((StatusCode::NOT_MODIFIED, response_headers).into_response());
+    }
+    response_headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_str(image.mime.as_ref()).unwrap(),
+    );
+
+    let length = match &full {
+        Some(body) => body.len() as u64,
+        None => state.original_length(image).await?,
+    };
+    let range = match headers.get(header::RANGE).and_then(|v| v.to_str().ok()) {
+        Some(range) if if_range(headers, &etag, &image.modified()) => {
+            ByteRange::parse(range, length)
+        }
+        _ => ByteRange::Full,
+    };
+    let (status, range) = match range {
+        ByteRange::Full => (StatusCode::OK, 0..length),
+        ByteRange::Partial(range) => {
+            let content_range = format!("bytes {}-{}/{}", range.start, range.end - 1, length);
+            response_headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&content_range).unwrap(),
+            );
+            (StatusCode::PARTIAL_CONTENT, range)
+        }
+        ByteRange::Unsatisfiable => {
+            let content_range = format!("bytes */{}", length);
+            response_headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&content_range).unwrap(),
+            );
+            return Ok((StatusCode::RANGE_NOT_SATISFIABLE, response_headers).into_response());
+        }
+    };
+
+    response_headers.insert(
+        header::CONTENT_LENGTH,
+        HeaderValue::from(range.end - range.start),
+    );
+    let body = match full {
+        Some(body) => Body::from(body.slice(range.start as usize..range.end as usize)),
+        None => Body::from_stream(state.original_stream(image, range).await?),
+    };
+    Ok((status, response_headers, body).into_response())
+}
+
+fn cache_headers(image: &ImagioImage, etag: &str, cache_control: HeaderValue) -> HeaderMap {
+    let last_modified = image.modified().format(HTTP_DATE).to_string();
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ETAG, HeaderValue::from_str(etag).unwrap());
+    headers.insert(
+        header::LAST_MODIFIED,
+        HeaderValue::from_str(&last_modified).unwrap(),
+    );
+    headers.insert(header::CACHE_CONTROL, cache_control);
+    headers
+}
+
+const HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+// Evaluate `If-None-Match`, falling back to `If-Modified-Since` only when the
+// former is absent (RFC 9110, section 13.2.2).
+fn not_modified(headers: &HeaderMap, etag: &str, modified: &DateTime<Utc>) -> bool {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    if let Some(if_none_match) = header(header::IF_NONE_MATCH) {
+        return if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag);
+    }
+    header(header::IF_MODIFIED_SINCE)
+        .and_then(|since| DateTime::parse_from_rfc2822(since).ok())
+        .is_some_and(|since| modified.timestamp() <= since.timestamp())
+}
+
+// `If-Range` only lets the range through when it still names the current
+// representation; otherwise the full body is sent.
+fn if_range(headers: &HeaderMap, etag: &str, modified: &DateTime<Utc>) -> bool {
+    match headers.get(header::IF_RANGE).and_then(|v| v.to_str().ok()) {
+        None => true,
+        Some(tag) if tag.starts_with('"') || tag.starts_with("W/") => tag == etag,
+        Some(date) => DateTime::parse_from_rfc2822(date)
+            .is_ok_and(|date| date.timestamp() == modified.timestamp()),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    Full,
+    Partial(Range<u64>),
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    // Only single `bytes` ranges are supported; anything else is ignored and
+    // answered with the full body.
+    fn parse(range: &str, length: u64) -> ByteRange {
+        let Some((start, end)) = range
+            .strip_prefix("bytes=")
+            .filter(|spec| !spec.contains(','))
+            .and_then(|spec| spec.trim().split_once('-'))
+        else {
+            return ByteRange::Full;
+        };
+        let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Err(_), Ok(0)) if start.is_empty() => return ByteRange::Unsatisfiable,
+            (Err(_), Ok(suffix)) if start.is_empty() => (length.saturating_sub(suffix), length),
+            (Ok(start), Err(_)) if end.is_empty() => (start, length),
+            (Ok(start), Ok(end)) if start <= end => (start, end.saturating_add(1).min(length)),
+            _ => return ByteRange::Full,
+        };
+        if start >= end || start >= length {
+            return ByteRange::Unsatisfiable;
+        }
+        ByteRange::Partial(start..end)
+    }
 }
 
 pub async fn server(state: Arc<ImagioState>) -> Result<(), ImagioError> {
     let listener = tokio::net::TcpListener::bind(&state.bind).await?;
-    let account_id = state.slug.clone();
 
     let app = Router::new()
         .route("/:uuid/:variant", get(uuid_handler))
-        .nest(
-            &format!("/{}", account_id),
-            Router::new().nest("/api", api_router(state.clone())),
-        )
+        .nest("/:account/api", api_router(state.clone()))
+        .fallback(|| async { ImagioError::NotFound })
+        .layer(middleware::map_response(method_not_allowed))
+        .layer(middleware::from_fn(request_id))
         .with_state(state);
 
     axum::serve(listener, app).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(header::HeaderName, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn modified() -> DateTime<Utc> {
+        "2024-05-01T12:00:00Z".parse().unwrap()
+    }
+
+    #[test]
+    fn parses_byte_ranges() {
+        use ByteRange::*;
+        let parse = |range| ByteRange::parse(range, 1000);
+        assert_eq!(parse("bytes=0-"), Partial(0..1000));
+        assert_eq!(parse("bytes=0-0"), Partial(0..1));
+        assert_eq!(parse("bytes=100-199"), Partial(100..200));
+        assert_eq!(parse("bytes=-500"), Partial(500..1000));
+        assert_eq!(parse("bytes=-2000"), Partial(0..1000));
+        assert_eq!(parse("bytes=900-5000"), Partial(900..1000));
+        assert_eq!(parse("bytes=-0"), Unsatisfiable);
+        assert_eq!(parse("bytes=1000-"), Unsatisfiable);
+        assert_eq!(parse("bytes=1000-1200"), Unsatisfiable);
+        // Ignored, answered with the full body
+        assert_eq!(parse("bytes=500-100"), Full);
+        assert_eq!(parse("bytes=0-1,5-6"), Full);
+        assert_eq!(parse("items=0-1"), Full);
+        assert_eq!(parse("bytes=a-b"), Full);
+        assert_eq!(parse("bytes=-"), Full);
+    }
+
+    #[test]
+    fn checks_if_range() {
+        let etag = "\"abc\"";
+        assert!(if_range(&headers(&[]), etag, &modified()));
+        assert!(if_range(
+            &headers(&[(header::IF_RANGE, "\"abc\"")]),
+            etag,
+            &modified()
+        ));
+        assert!(!if_range(
+            &headers(&[(header::IF_RANGE, "\"def\"")]),
+            etag,
+            &modified()
+        ));
+        // Weak validators never match for ranges
+        assert!(!if_range(
+            &headers(&[(header::IF_RANGE, "W/\"abc\"")]),
+            etag,
+            &modified()
+        ));
+        assert!(if_range(
+            &headers(&[(header::IF_RANGE, "Wed, 01 May 2024 12:00:00 GMT")]),
+            etag,
+            &modified()
+        ));
+        assert!(!if_range(
+            &headers(&[(header::IF_RANGE, "Wed, 01 May 2024 11:59:59 GMT")]),
+            etag,
+            &modified()
+        ));
+        assert!(!if_range(
+            &headers(&[(header::IF_RANGE, "yesterday")]),
+            etag,
+            &modified()
+        ));
+    }
+
+    #[test]
+    fn checks_not_modified() {
+        let etag = "\"abc\"";
+        let check =
+            |pairs: &[(header::HeaderName, &str)]| not_modified(&headers(pairs), etag, &modified());
+        assert!(!check(&[]));
+
+        // Entity tags compare weakly
+        assert!(check(&[(header::IF_NONE_MATCH, "\"abc\"")]));
+        assert!(check(&[(header::IF_NONE_MATCH, "W/\"abc\"")]));
+        assert!(check(&[(header::IF_NONE_MATCH, "\"x\", \"abc\"")]));
+        assert!(check(&[(header::IF_NONE_MATCH, "*")]));
+        assert!(!check(&[(header::IF_NONE_MATCH, "\"x\"")]));
+        assert!(!check(&[(header::IF_NONE_MATCH, "abc")]));
+
+        let since = "Wed, 01 May 2024 12:00:00 GMT";
+        assert!(check(&[(header::IF_MODIFIED_SINCE, since)]));
+        assert!(check(&[(
+            header::IF_MODIFIED_SINCE,
+            "Thu, 02 May 2024 00:00:00 GMT"
+        )]));
+        assert!(!check(&[(
+            header::IF_MODIFIED_SINCE,
+            "Wed, 01 May 2024 11:59:59 GMT"
+        )]));
+        assert!(!check(&[(header::IF_MODIFIED_SINCE, "yesterday")]));
+
+        // If-None-Match takes precedence over If-Modified-Since
+        assert!(!check(&[
+            (header::IF_NONE_MATCH, "\"x\""),
+            (header::IF_MODIFIED_SINCE, since),
+        ]));
+        assert!(check(&[
+            (header::IF_NONE_MATCH, "\"abc\""),
+            (header::IF_MODIFIED_SINCE, "Wed, 01 May 2024 11:00:00 GMT"),
+        ]));
+    }
+}
@@ -0,0 +1,145 @@
+use rusqlite::{Connection, OptionalExtension};
+use serde::Deserialize;
+
+use crate::{account::ImagioAccount, ImagioError, ImagioImage, ImagioState};
+
+const MAX_TAG_LEN: usize = 64;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TagList {
+    pub(crate) tags: Vec<String>,
+}
+
+// Tags are compared case-insensitively, so they are stored in lowercase.
+// Commas separate tags in listings and are not allowed in them.
+pub(crate) fn normalize_tag(tag: &str) -> Result<String, ImagioError> {
+    let tag = tag.trim().to_lowercase();
+    if tag.is_empty()
+        || tag.chars().count() > MAX_TAG_LEN
+        || tag.chars().any(|c| c == ',' || c.is_control())
+    {
+        return Err(ImagioError::InvalidInput(format!("invalid tag: {:?}", tag)));
+    }
+    Ok(tag)
+}
+
+fn image_id(conn: &Connection, account: &ImagioAccount, uuid: &str) -> Result<i64, ImagioError> {
+    conn.query_row(
+        "SELECT id FROM images WHERE account_id = ? AND uuid = ? AND deleted_at IS NULL",
+        (account.id, uuid),
+        |row| row.get(0),
+    )
+    .optional()?
+    .ok_or(ImagioError::NotFound)
+}
+
+fn add_tags(
+    conn: &Connection,
+    account: &ImagioAccount,
+    image_id: i64,
+    tags: &[String],
+) -> Result<(), ImagioError> {
+    for tag in tags {
+        conn.execute(
+            "INSERT OR IGNORE INTO tags (account_id, name) VALUES (?, ?)",
+            (account.id, tag),
+        )?;
+        conn.execute(
+            "INSERT OR IGNORE INTO image_tags (image_id, tag_id) \
+            SELECT ?, id FROM tags WHERE account_id = ? AND name = ?",
+            (image_id, account.id, tag),
+        )?;
+    }
+    Ok(())
+}
+
+impl ImagioState {
+    // Tag an image, keeping the tags it already has.
+    pub(crate) async fn tag(
+        &self,
+        account: &ImagioAccount,
+        uuid: &str,
+        tags: &[String],
+    ) -> Result<ImagioImage, ImagioError> {
+        let tags = tags
+            .iter()
+            .map(|tag| normalize_tag(tag))
+            .collect::<Result<Vec<_>, _>>()?;
+        {
+            let lock = self.db.write().await;
+            let conn = &mut lock.lock().await;
+            let tx = conn.transaction()?;
+            let image_id = image_id(&tx, account, uuid)?;
+            add_tags(&tx, account, image_id, &tags)?;
+            tx.commit()?;
+        }
+        self.get(account, uuid).await
+    }
+
+    // Replace all tags of an image.
+    pub(crate) async fn set_tags(
+        &self,
+        account: &ImagioAccount,
+        uuid: &str,
+        tags: &[String],
+    ) -> Result<ImagioImage, ImagioError> {
+        let tags = tags
+            .iter()
+            .map(|tag| normalize_tag(tag))
+            .collect::<Result<Vec<_>, _>>()?;
+        {
+            let lock = self.db.write().await;
+            let conn = &mut lock.lock().await;
+            let tx = conn.transaction()?;
+            let image_id = image_id(&tx, account, uuid)?;
+            tx.execute("DELETE FROM image_tags WHERE image_id = ?", [image_id])?;
+            add_tags(&tx, account, image_id, &tags)?;
+            tx.commit()?;
+        }
+        self.get(account, uuid).await
+    }
+
+    pub(crate) async fn untag(
+        &self,
+        account: &ImagioAccount,
+        uuid: &str,
+        tag: &str,
+    ) -> Result<(), ImagioError> {
+        let tag = normalize_tag(tag)?;
+        let lock = self.db.write().await;
+        let conn = &lock.lock().await;
+        let image_id = image_id(conn, account, uuid)?;
+        let removed = conn.execute(
+            "DELETE FROM image_tags WHERE image_id = ? AND tag_id = \
+            (SELECT id FROM tags WHERE account_id = ? AND name = ?)",
+            (image_id, account.id, &tag),
+        )?;
+        if removed == 0 {
+            return Err(ImagioError::NotFound);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_tags() {
+        assert_eq!(normalize_tag("Beach").unwrap(), "beach");
+        assert_eq!(normalize_tag("  Sunset Sky ").unwrap(), "sunset sky");
+        assert_eq!(normalize_tag("ÉTÉ").unwrap(), "été");
+        assert_eq!(normalize_tag(&"a".repeat(64)).unwrap().len(), 64);
+        assert_eq!(normalize_tag(&"é".repeat(64)).unwrap().chars().count(), 64);
+    }
+
+    #[test]
+    fn rejects_invalid_tags() {
+        for tag in ["", "   ", "a,b", "tab\there", "new\nline"] {
+            assert!(normalize_tag(tag).is_err(), "{:?}", tag);
+        }
+        assert!(normalize_tag(&"a".repeat(65)).is_err());
+    }
+}
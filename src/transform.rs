@@ -0,0 +1,420 @@
+use std::io::BufWriter;
+use std::str::FromStr;
+
+use axum::body::Bytes;
+use fast_image_resize::images::Image;
+use fast_image_resize::{IntoImageView, ResizeOptions, Resizer};
+use image::codecs::avif::AvifEncoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::{DynamicImage, GenericImageView, ImageEncoder};
+use mime_guess::Mime;
+use serde::{Deserialize, Serialize};
+
+use crate::ImagioError;
+
+const MAX_DIMENSION: u32 = 8192;
+const DEFAULT_QUALITY: u8 = 80;
+const AVIF_SPEED: u8 = 8;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Fit {
+    #[default]
+    Cover,
+    Contain,
+    Fill,
+    ScaleDown,
+}
+
+impl FromStr for Fit {
+    type Err = ImagioError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cover" => Ok(Fit::Cover),
+            "contain" => Ok(Fit::Contain),
+            "fill" => Ok(Fit::Fill),
+            "scale-down" => Ok(Fit::ScaleDown),
+            _ => Err(ImagioError::InvalidVariant(format!("unknown fit: {}", s))),
+        }
+    }
+}
+
+impl std::fmt::Display for Fit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Fit::Cover => write!(f, "cover"),
+            Fit::Contain => write!(f, "contain"),
+            Fit::Fill => write!(f, "fill"),
+            Fit::ScaleDown => write!(f, "scale-down"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Png,
+    #[serde(alias = "jpg")]
+    Jpeg,
+    Webp,
+    Avif,
+}
+
+impl OutputFormat {
+    pub fn from_mime(mime: &Mime) -> Self {
+        match mime.subtype().as_str() {
+            "jpeg" => OutputFormat::Jpeg,
+            "webp" => OutputFormat::Webp,
+            "avif" => OutputFormat::Avif,
+            _ => OutputFormat::Png,
+        }
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "image/png",
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Webp => "image/webp",
+            OutputFormat::Avif => "image/avif",
+        }
+    }
+
+    // Whether an `Accept` header explicitly lists this format with q > 0.
+    // Wildcards are ignored: they say nothing about newer formats.
+    pub fn accepted_by(&self, accept: &str) -> bool {
+        accept.split(',').any(|range| {
+            let mut params = range.split(';');
+            let media_type = params.next().unwrap_or_default().trim();
+            let q = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            media_type.eq_ignore_ascii_case(self.mime()) && q > 0.0
+        })
+    }
+
+    pub fn ext(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "PNG",
+            OutputFormat::Jpeg => "JPEG",
+            OutputFormat::Webp => "WEBP",
+            OutputFormat::Avif => "AVIF",
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = ImagioError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "png" => Ok(OutputFormat::Png),
+            "jpeg" | "jpg" => Ok(OutputFormat::Jpeg),
+            "webp" => Ok(OutputFormat::Webp),
+            "avif" => Ok(OutputFormat::Avif),
+            _ => Err(ImagioError::InvalidVariant(format!(
+                "unknown format: {}",
+                s
+            ))),
+        }
+    }
+}
+
+impl std::fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputFormat::Png => write!(f, "png"),
+            OutputFormat::Jpeg => write!(f, "jpeg"),
+            OutputFormat::Webp => write!(f, "webp"),
+            OutputFormat::Avif => write!(f, "avif"),
+        }
+    }
+}
+
+// A resize/re-encode request such as `w=640,h=480,fit=cover,f=webp,q=80`.
+// Parsing normalizes the spec so that equivalent requests print (and cache)
+// under the same canonical string.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransformSpec {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: Fit,
+    pub format: Option<OutputFormat>,
+    pub quality: Option<u8>,
+}
+
+impl FromStr for TransformSpec {
+    type Err = ImagioError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |msg: String| ImagioError::InvalidVariant(msg);
+
+        let mut spec = TransformSpec::default();
+        let mut fit = None;
+        for part in s.split(',') {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("expected key=value: {}", part)))?;
+            let duplicate = match key {
+                "w" | "width" => spec.width.replace(number(value)?).is_some(),
+                "h" | "height" => spec.height.replace(number(value)?).is_some(),
+                "fit" => fit.replace(value.parse()?).is_some(),
+                "f" | "format" => spec.format.replace(value.parse()?).is_some(),
+                "q" | "quality" => spec.quality.replace(number(value)?).is_some(),
+                _ => return Err(invalid(format!("unknown parameter: {}", key))),
+            };
+            if duplicate {
+                return Err(invalid(format!("duplicate parameter: {}", key)));
+            }
+        }
+        spec.fit = fit.unwrap_or_default();
+        spec.validated()
+    }
+}
+
+impl std::fmt::Display for TransformSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(w) = self.width {
+            parts.push(format!("w={}", w));
+        }
+        if let Some(h) = self.height {
+            parts.push(format!("h={}", h));
+        }
+        if self.fit != Fit::default() {
+            parts.push(format!("fit={}", self.fit));
+        }
+        if let Some(format) = self.format {
+            parts.push(format!("f={}", format));
+        }
+        if let Some(q) = self.quality {
+            parts.push(format!("q={}", q));
+        }
+        write!(f, "{}", parts.join(","))
+    }
+}
+
+impl TransformSpec {
+    pub fn validated(self) -> Result<Self, ImagioError> {
+        let dimensions = [self.width, self.height];
+        if let Some(n) = dimensions
+            .into_iter()
+            .flatten()
+            .find(|n| !(1..=MAX_DIMENSION).contains(n))
+        {
+            return Err(ImagioError::InvalidVariant(format!(
+                "invalid dimension: {}",
+                n
+            )));
+        }
+        if let Some(q) = self.quality.filter(|q| !(1..=100).contains(q)) {
+            return Err(ImagioError::InvalidVariant(format!(
+                "invalid quality: {}",
+                q
+            )));
+        }
+        Ok(self.normalized())
+    }
+
+    pub fn normalized(mut self) -> Self {
+        // Only `scale-down` changes the result unless both sides are fixed
+        if (self.width.is_none() || self.height.is_none()) && self.fit != Fit::ScaleDown {
+            self.fit = Fit::default();
+        }
+        // Lossless formats ignore the quality
+        if matches!(self.format, Some(OutputFormat::Png | OutputFormat::Webp)) {
+            self.quality = None;
+        }
+        self
+    }
+
+    // Size of the rendered image for an original of the given size
+    pub(crate) fn dimensions(&self, (width, height): (u32, u32)) -> (u32, u32) {
+        let (w_ratio, h_ratio) = (
+            self.width.map(|w| w as f64 / width as f64),
+            self.height.map(|h| h as f64 / height as f64),
+        );
+        let ratio = match (w_ratio, h_ratio) {
+            (None, None) => 1.0,
+            (Some(r), None) | (None, Some(r)) => r,
+            (Some(w), Some(h)) => match self.fit {
+                Fit::Cover | Fit::Fill => return (self.width.unwrap(), self.height.unwrap()),
+                Fit::Contain | Fit::ScaleDown => w.min(h),
+            },
+        };
+        let ratio = match self.fit {
+            Fit::ScaleDown => ratio.min(1.0),
+            _ => ratio,
+        };
+        // Shrink both sides alike to keep the longer one within bounds
+        let ratio = ratio.min(MAX_DIMENSION as f64 / width.max(height) as f64);
+        let scale = |n: u32| ((n as f64 * ratio).round() as u32).clamp(1, MAX_DIMENSION);
+        (scale(width), scale(height))
+    }
+
+    pub fn apply(&self, img: DynamicImage, format: OutputFormat) -> Result<Bytes, ImagioError> {
+        let img = prepare(img, format);
+        let (dst_width, dst_height) = self.dimensions(img.dimensions());
+        let pixel_type = img
+            .pixel_type()
+            .ok_or(fast_image_resize::ImageError::UnsupportedPixelType)
+            .map_err(fast_image_resize::ResizeError::from)?;
+        // Create container for data of destination image
+        let mut dst_image = Image::new(dst_width, dst_height, pixel_type);
+
+        let options = match self.fit {
+            Fit::Cover => ResizeOptions::new().fit_into_destination(None),
+            _ => ResizeOptions::new(),
+        };
+        Resizer::new().resize(&img, &mut dst_image, &options)?;
+
+        tracing::info!("Starting encoding to {}.", format);
+        let quality = self.quality.unwrap_or(DEFAULT_QUALITY);
+        let mut result_buf = BufWriter::new(Vec::new());
+        let (buf, color) = (dst_image.buffer(), img.color().into());
+        match format {
+            OutputFormat::Png => {
+                PngEncoder::new(&mut result_buf).write_image(buf, dst_width, dst_height, color)?
+            }
+            OutputFormat::Jpeg => JpegEncoder::new_with_quality(&mut result_buf, quality)
+                .write_image(buf, dst_width, dst_height, color)?,
+            // The bundled WebP encoder is lossless only, so quality does not apply
+            OutputFormat::Webp => WebPEncoder::new_lossless(&mut result_buf)
+                .write_image(buf, dst_width, dst_height, color)?,
+            OutputFormat::Avif => {
+                AvifEncoder::new_with_speed_quality(&mut result_buf, AVIF_SPEED, quality)
+                    .write_image(buf, dst_width, dst_height, color)?
+            }
+        }
+        tracing::info!("Finished encoding to {}.", format);
+
+        // Return the bytes in the buffer
+        Ok(Bytes::from(
+            result_buf.into_inner().map_err(|e| e.into_error())?,
+        ))
+    }
+}
+
+fn number<T: FromStr>(value: &str) -> Result<T, ImagioError> {
+    value
+        .parse()
+        .map_err(|_| ImagioError::InvalidVariant(format!("invalid number: {}", value)))
+}
+
+// Convert the decoded image into a pixel layout the target encoder accepts.
+fn prepare(img: DynamicImage, format: OutputFormat) -> DynamicImage {
+    use DynamicImage::*;
+    match (format, img) {
+        (
+            OutputFormat::Png,
+            img @ (ImageLuma8(_) | ImageLumaA8(_) | ImageRgb8(_) | ImageRgba8(_) | ImageLuma16(_)
+            | ImageLumaA16(_) | ImageRgb16(_) | ImageRgba16(_)),
+        ) => img,
+        (OutputFormat::Jpeg, img @ ImageLuma8(_)) => img,
+        (OutputFormat::Jpeg, img) => ImageRgb8(img.to_rgb8()),
+        (_, img) if img.color().has_alpha() => ImageRgba8(img.to_rgba8()),
+        (_, img) => ImageRgb8(img.to_rgb8()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(s: &str) -> TransformSpec {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_aliases_into_canonical_form() {
+        assert_eq!(
+            spec("width=640,height=480,fit=contain,format=jpg,quality=70").to_string(),
+            "w=640,h=480,fit=contain,f=jpeg,q=70"
+        );
+        assert_eq!(spec("q=80,f=avif,w=100").to_string(), "w=100,f=avif,q=80");
+        assert_eq!(spec("w=100"), spec("width=100"));
+    }
+
+    #[test]
+    fn matches_accept_headers() {
+        let webp = OutputFormat::Webp;
+        assert!(webp.accepted_by("image/webp"));
+        assert!(webp.accepted_by("image/avif, IMAGE/WEBP;q=0.5, */*;q=0.1"));
+        assert!(webp.accepted_by("image/webp;q=1.0"));
+        assert!(!webp.accepted_by("image/webp;q=0"));
+        assert!(!webp.accepted_by("image/webp; q=0.0"));
+        // Wildcards do not count
+        assert!(!webp.accepted_by("image/*"));
+        assert!(!webp.accepted_by("*/*"));
+        assert!(!webp.accepted_by(""));
+        assert!(!OutputFormat::Avif.accepted_by("image/webp"));
+    }
+
+    #[test]
+    fn rejects_invalid_specs() {
+        for s in [
+            "",
+            "w",
+            "w=0",
+            "w=8193",
+            "h=-1",
+            "w=abc",
+            "q=0",
+            "q=101",
+            "fit=stretch",
+            "f=gif",
+            "x=1",
+            "w=1,w=2",
+            "w=1,width=2",
+        ] {
+            assert!(s.parse::<TransformSpec>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn normalizes_equivalent_specs() {
+        // Only scale-down matters without both sides
+        assert_eq!(spec("w=100,fit=contain"), spec("w=100"));
+        assert_eq!(spec("h=100,fit=fill"), spec("h=100"));
+        assert_ne!(spec("w=100,fit=scale-down"), spec("w=100"));
+        assert_ne!(spec("w=100,h=100,fit=contain"), spec("w=100,h=100"));
+        // Lossless formats ignore the quality
+        assert_eq!(spec("f=png,q=80"), spec("f=png"));
+        assert_eq!(spec("f=webp,q=80"), spec("f=webp"));
+        assert_ne!(spec("f=jpeg,q=80"), spec("f=jpeg"));
+    }
+
+    #[test]
+    fn computes_dimensions() {
+        let original = (2000, 1000);
+        assert_eq!(spec("w=500").dimensions(original), (500, 250));
+        assert_eq!(spec("h=100").dimensions(original), (200, 100));
+        assert_eq!(spec("w=500,h=500").dimensions(original), (500, 500));
+        assert_eq!(
+            spec("w=500,h=500,fit=fill").dimensions(original),
+            (500, 500)
+        );
+        assert_eq!(
+            spec("w=500,h=500,fit=contain").dimensions(original),
+            (500, 250)
+        );
+        assert_eq!(spec("w=4000").dimensions(original), (4000, 2000));
+        assert_eq!(
+            spec("w=4000,fit=scale-down").dimensions(original),
+            (2000, 1000)
+        );
+        assert_eq!(spec("f=webp").dimensions(original), (2000, 1000));
+        assert_eq!(spec("w=1").dimensions((1000, 1)), (1, 1));
+    }
+
+    #[test]
+    fn keeps_aspect_ratio_within_bounds() {
+        assert_eq!(spec("f=webp").dimensions((16000, 4000)), (8192, 2048));
+        assert_eq!(spec("h=8000").dimensions((1000, 4000)), (2000, 8000));
+        assert_eq!(spec("h=4000").dimensions((16000, 2000)), (8192, 1024));
+    }
+}
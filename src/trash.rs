@@ -0,0 +1,143 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::Utc;
+
+use crate::{
+    account::ImagioAccount,
+    app::{release_blob, SELECT_IMAGES},
+    deletion::{schedule_deletion, Storage},
+    ImagioError, ImagioImage, ImagioState,
+};
+
+// How often images past the trash retention are looked for
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+impl ImagioState {
+    // Move an image to the trash. It is hidden until restored and purged
+    // once the retention has passed; its rendered variants are dropped now.
+    pub(crate) async fn delete(
+        &self,
+        account: &ImagioAccount,
+        uuid: &str,
+    ) -> Result<ImagioImage, ImagioError> {
+        let mut image = self.get(account, uuid).await?;
+        image.deleted_at = Some(Utc::now());
+        {
+            let lock = self.db.write().await;
+            let conn = &mut lock.lock().await;
+            let tx = conn.transaction()?;
+            // A concurrent delete may have won the race since the lookup
+            let trashed = tx.execute(
+                "UPDATE images SET deleted_at = ? WHERE uuid = ? AND deleted_at IS NULL",
+                (image.deleted_at.map(|t| t.to_string()), &image.uuid),
+            )?;
+            if trashed == 0 {
+                return Err(ImagioError::NotFound);
+            }
+            schedule_deletion(&tx, Storage::Cache, &image.cache_dir())?;
+            tx.commit()?;
+        }
+        tracing::info!("Image {} moved to the trash", image.uuid);
+        self.run_deletions().await;
+        Ok(image)
+    }
+
+    pub(crate) async fn restore(
+        &self,
+        account: &ImagioAccount,
+        uuid: &str,
+    ) -> Result<ImagioImage, ImagioError> {
+        let lock = self.db.write().await;
+        let conn = &lock.lock().await;
+        let restored = conn.execute(
+            "UPDATE images SET deleted_at = NULL \
+            WHERE account_id = ? AND uuid = ? AND deleted_at IS NOT NULL",
+            (account.id, uuid),
+        )?;
+        if restored == 0 {
+            return Err(ImagioError::NotFound);
+        }
+        let mut stmt = conn.prepare(&format!("{} WHERE images.uuid = ?", SELECT_IMAGES))?;
+        let image = stmt.query_row([uuid], |row| Ok(ImagioImage::try_from(row)))??;
+        tracing::info!("Image {} restored from the trash", image.uuid);
+        Ok(image)
+    }
+
+    pub(crate) async fn list_trash(
+        &self,
+        account: &ImagioAccount,
+        limit: usize,
+        skip: usize,
+    ) -> Result<Vec<ImagioImage>, ImagioError> {
+        let lock = self.db.read().await;
+        let conn = &lock.lock().await;
+        let mut stmt = conn.prepare(&format!(
+            "{} WHERE images.account_id = ? AND images.deleted_at IS NOT NULL \
+            ORDER BY images.deleted_at DESC LIMIT ? OFFSET ?",
+            SELECT_IMAGES
+        ))?;
+        let mut rows = stmt.query((account.id, limit as i64, skip as i64))?;
+
+        let mut images = Vec::new();
+        while let Some(row) = rows.next()? {
+            let image = ImagioImage::try_from(row)?;
+            images.push(image);
+        }
+
+        Ok(images)
+    }
+
+    // Delete the images that have been in the trash for longer than the
+    // retention, along with their files. Returns how many were purged.
+    pub(crate) async fn purge_trash(&self) -> Result<usize, ImagioError> {
+        let cutoff = Utc::now() - self.trash_retention;
+        let images = {
+            let lock = self.db.read().await;
+            let conn = &lock.lock().await;
+            let mut stmt = conn.prepare(&format!(
+                "{} WHERE images.deleted_at IS NOT NULL AND images.deleted_at < ?",
+                SELECT_IMAGES
+            ))?;
+            let rows =
+                stmt.query_map([cutoff.to_string()], |row| Ok(ImagioImage::try_from(row)))?;
+            rows.collect::<Result<Result<Vec<_>, _>, _>>()??
+        };
+
+        let mut purged = 0;
+        for image in images {
+            let lock = self.db.write().await;
+            let conn = &mut lock.lock().await;
+            let tx = conn.transaction()?;
+            // Skip images restored in the meantime
+            if tx.execute(
+                "DELETE FROM images WHERE uuid = ? AND deleted_at IS NOT NULL",
+                [&image.uuid],
+            )? == 0
+            {
+                continue;
+            }
+            if let Some(filename) = release_blob(&tx, &image)? {
+                schedule_deletion(&tx, Storage::Store, &filename)?;
+            }
+            schedule_deletion(&tx, Storage::Cache, &image.cache_dir())?;
+            tx.commit()?;
+            tracing::info!("Image {} purged from the trash", image.uuid);
+            purged += 1;
+        }
+        if purged > 0 {
+            self.run_deletions().await;
+        }
+        Ok(purged)
+    }
+
+    // Purge the trash now and then, for as long as the server runs.
+    pub(crate) async fn purge_periodically(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(err) = self.purge_trash().await {
+                tracing::warn!("Purging the trash failed: {}", err);
+            }
+        }
+    }
+}
@@ -1,113 +1,240 @@
-use image::codecs::jpeg::JpegEncoder;
-use image::codecs::png::PngEncoder;
 use image::io::Reader as ImageReader;
-use image::{ColorType, DynamicImage, GenericImageView, ImageEncoder};
-use std::io::BufWriter;
-
-use fast_image_resize::images::Image;
-use fast_image_resize::{IntoImageView, ResizeOptions, Resizer};
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Instant;
 
 use axum::body::Bytes;
-use serde::{Deserialize, Serialize};
+use opendal::FuturesAsyncReader;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt};
+use tokio_util::io::ReaderStream;
 
 use crate::app::ImagioImage;
+use crate::category::ImagioCategory;
+use crate::transform::{Fit, OutputFormat, TransformSpec};
 use crate::{ImagioError, ImagioState};
 
-#[derive(Debug, Default, Serialize, Deserialize, Clone)]
-#[serde(rename_all = "lowercase")]
+#[derive(Debug, Default, Clone)]
 pub enum Variant {
-    Public,
-    Embed,
-    Thumb,
-    Banner,
-    Square,
+    Preset(String, TransformSpec),
+    Custom(TransformSpec),
     #[default]
     Original,
 }
 
-impl From<&str> for Variant {
-    fn from(s: &str) -> Self {
-        match s {
-            "public" => Variant::Public,
-            "thumb" => Variant::Thumb,
-            "banner" => Variant::Banner,
-            "square" => Variant::Square,
-            "embed" => Variant::Embed,
-            _ => Variant::Original,
-        }
-    }
-}
-
 impl std::fmt::Display for Variant {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Variant::Public => write!(f, "public"),
-            Variant::Thumb => write!(f, "thumb"),
-            Variant::Square => write!(f, "square"),
-            Variant::Banner => write!(f, "banner"),
-            Variant::Embed => write!(f, "embed"),
+            Variant::Preset(name, _) => write!(f, "{}", name),
+            Variant::Custom(spec) => write!(f, "{}", spec),
             Variant::Original => write!(f, "original"),
         }
     }
 }
 
 impl Variant {
-    pub fn transform(&self, img: DynamicImage) -> Bytes {
-        let (width, height) = img.dimensions();
-        // Create container for data of destination image
-        let (dst_width, dst_height) = match self {
-            Variant::Public => (1024, 768),
-            Variant::Embed => (width.min(1024), height * width.min(1024) / width),
-            Variant::Thumb => (256, 256),
-            Variant::Banner => (800, 400),
-            Variant::Square => (320, 320),
-            Variant::Original => unreachable!(),
+    pub fn spec(&self) -> Option<&TransformSpec> {
+        match self {
+            Variant::Preset(_, spec) | Variant::Custom(spec) => Some(spec),
+            Variant::Original => None,
+        }
+    }
+
+    // Pick the first preferred format the client accepts, unless the
+    // variant already fixes its output format.
+    pub fn negotiate(self, accept: &str, preferred: &[OutputFormat]) -> Variant {
+        match preferred.iter().find(|format| format.accepted_by(accept)) {
+            Some(format) => self.with_format(*format),
+            None => self,
+        }
+    }
+
+    // Set the output format, unless the variant already fixes one.
+    pub fn with_format(self, format: OutputFormat) -> Variant {
+        let format = match self.spec() {
+            Some(spec) if spec.format.is_none() => Some(format),
+            _ => None,
         };
-        // Create container for data of destination image
-        let mut dst_image = Image::new(dst_width, dst_height, img.pixel_type().unwrap());
-
-        let mut resizer = Resizer::new();
-        resizer
-            .resize(
-                &img,
-                &mut dst_image,
-                &ResizeOptions::new().fit_into_destination(None),
-            )
-            .unwrap();
-
-        // Write destination image as PNG-file
-        tracing::info!("Starting encoding to Jpeg.");
-        let mut result_buf = BufWriter::new(Vec::new());
-        match img.color() {
-            ColorType::Rgba8 | ColorType::Rgb16 => {
-                PngEncoder::new(&mut result_buf)
-                    .write_image(
-                        dst_image.buffer(),
-                        dst_width,
-                        dst_height,
-                        img.color().into(),
-                    )
-                    .unwrap();
-            }
-            _ => {
-                JpegEncoder::new(&mut result_buf)
-                    .write_image(
-                        dst_image.buffer(),
-                        dst_width,
-                        dst_height,
-                        img.color().into(),
-                    )
-                    .unwrap();
+        match (self, format) {
+            (Variant::Preset(name, spec), Some(format)) => Variant::Preset(
+                name,
+                TransformSpec {
+                    format: Some(format),
+                    ..spec
+                }
+                .normalized(),
+            ),
+            (Variant::Custom(spec), Some(format)) => Variant::Custom(
+                TransformSpec {
+                    format: Some(format),
+                    ..spec
+                }
+                .normalized(),
+            ),
+            (variant, _) => variant,
+        }
+    }
+
+    pub fn negotiable(&self) -> bool {
+        self.spec().is_some_and(|spec| spec.format.is_none())
+    }
+
+    // Output format of the rendered variant: explicit in the spec, otherwise
+    // the format of the original upload.
+    pub fn format(&self, image: &ImagioImage) -> OutputFormat {
+        self.spec()
+            .and_then(|spec| spec.format)
+            .unwrap_or_else(|| OutputFormat::from_mime(&image.mime))
+    }
+}
+
+// Named variants served under `/:uuid/:name`. The built-in presets can be
+// overridden or extended by a TOML or JSON file mapping names to specs.
+#[derive(Debug, Clone)]
+pub struct VariantPresets(BTreeMap<String, TransformSpec>);
+
+impl Default for VariantPresets {
+    fn default() -> Self {
+        let sized = |width, height, fit| TransformSpec {
+            width: Some(width),
+            height,
+            fit,
+            ..Default::default()
+        };
+        VariantPresets(BTreeMap::from([
+            ("public".to_string(), sized(1024, Some(768), Fit::Cover)),
+            ("embed".to_string(), sized(1024, None, Fit::ScaleDown)),
+            ("thumb".to_string(), sized(256, Some(256), Fit::Cover)),
+            ("banner".to_string(), sized(800, Some(400), Fit::Cover)),
+            ("square".to_string(), sized(320, Some(320), Fit::Cover)),
+        ]))
+    }
+}
+
+impl VariantPresets {
+    pub fn load(path: &str) -> Result<Self, ImagioError> {
+        let content = std::fs::read_to_string(path)?;
+        let presets: BTreeMap<String, TransformSpec> = if path.ends_with(".json") {
+            serde_json::from_str(&content).map_err(|e| ImagioError::ConfigError(e.to_string()))?
+        } else {
+            toml::from_str(&content).map_err(|e| ImagioError::ConfigError(e.to_string()))?
+        };
+
+        let mut variants = VariantPresets::default();
+        for (name, spec) in presets {
+            let valid_name = name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if name.is_empty() || name == "original" || !valid_name {
+                return Err(ImagioError::ConfigError(format!(
+                    "invalid variant name: {:?}",
+                    name
+                )));
             }
+            let spec = spec
+                .validated()
+                .map_err(|e| ImagioError::ConfigError(format!("variant {}: {}", name, e)))?;
+            variants.0.insert(name, spec);
+        }
+        tracing::info!("Loaded {} variant presets from: {}", variants.0.len(), path);
+        Ok(variants)
+    }
+
+    pub fn resolve(&self, name: &str) -> Result<Variant, ImagioError> {
+        if name == "original" {
+            return Ok(Variant::Original);
         }
-        tracing::info!("Finished encoding to Jpeg.");
+        if name.contains('=') {
+            return Ok(Variant::Custom(name.parse()?));
+        }
+        self.0
+            .get(name)
+            .map(|spec| Variant::Preset(name.to_string(), spec.clone()))
+            .ok_or(ImagioError::NotFound)
+    }
 
-        // Return the bytes in the buffer
-        Bytes::from(result_buf.into_inner().unwrap())
+    pub fn all(&self) -> Vec<Variant> {
+        self.0
+            .iter()
+            .map(|(name, spec)| Variant::Preset(name.clone(), spec.clone()))
+            .collect()
     }
 }
 
+// How long signed URLs handed out with images stay valid
+const LINK_SIGN_TTL: u32 = 3600;
+
 impl ImagioState {
+    // URLs of the presets, and the original, that the image's category
+    // serves. Images in private categories get signed URLs, or none without
+    // a signing key.
+    pub(crate) fn variant_urls(
+        &self,
+        image: &ImagioImage,
+        category: &ImagioCategory,
+    ) -> BTreeMap<String, String> {
+        let private = self.is_private(category);
+        std::iter::once(Variant::Original)
+            .chain(self.variants.all())
+            .filter(|variant| category.allows(variant))
+            .filter_map(|variant| {
+                let name = variant.to_string();
+                let path = match (private, &self.signer) {
+                    (false, _) => format!("/{}/{}", image.uuid, name),
+                    (true, Some(signer)) => signer.signed_path(&image.uuid, &name, LINK_SIGN_TTL).0,
+                    (true, None) => return None,
+                };
+                Some((name, format!("{}{}", self.public_url, path)))
+            })
+            .collect()
+    }
+
+    // Candidates of the `--srcset-variant` presets the image is served as,
+    // narrowest first, described by the width they render at. Without the
+    // size of the original that width is unknown and there is no srcset.
+    fn srcset(&self, image: &ImagioImage, urls: &BTreeMap<String, String>) -> Option<String> {
+        let size = image.metadata.width.zip(image.metadata.height)?;
+        let mut candidates: Vec<(u32, &String)> = Vec::new();
+        for (name, spec) in &self.srcset_variants {
+            let (Some(url), (width, _)) = (urls.get(name), spec.dimensions(size)) else {
+                continue;
+            };
+            // Browsers pick by width, so one candidate per width
+            if !candidates.iter().any(|(w, _)| *w == width) {
+                candidates.push((width, url));
+            }
+        }
+        candidates.sort_by_key(|(width, _)| *width);
+        let candidates = candidates
+            .iter()
+            .map(|(width, url)| format!("{} {}w", url, width))
+            .collect::<Vec<_>>();
+        (!candidates.is_empty()).then(|| candidates.join(", "))
+    }
+
+    // Fill in the URLs of images for an API response. Images in the trash
+    // are not served and get none.
+    pub(crate) async fn link(&self, images: &mut [ImagioImage]) -> Result<(), ImagioError> {
+        let mut categories: HashMap<(i64, String), ImagioCategory> = HashMap::new();
+        for image in images.iter_mut().filter(|image| image.deleted_at.is_none()) {
+            let key = (image.account_id, image.category.clone());
+            if !categories.contains_key(&key) {
+                let category = self.category(image.account_id, &image.category).await?;
+                categories.insert(key.clone(), category);
+            }
+            image.variants = self.variant_urls(image, &categories[&key]);
+            image.srcset = self.srcset(image, &image.variants);
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn linked(&self, mut image: ImagioImage) -> Result<ImagioImage, ImagioError> {
+        self.link(std::slice::from_mut(&mut image)).await?;
+        Ok(image)
+    }
+
     async fn variant_raw(
         &self,
         image: &ImagioImage,
@@ -127,22 +254,50 @@ impl ImagioState {
                     let buf = self.storage.cache.read(&filename).await?;
                     return Ok(buf.to_bytes());
                 }
-                let original = image.filename(&Variant::Original);
-                let buf = self.storage.store.read(&original).await?;
-
-                let img = ImageReader::new(std::io::Cursor::new(buf.to_bytes()))
-                    .with_guessed_format()?
-                    .decode()?;
-                let bytes = variant.transform(img);
-                // Write the variant image to the store
-                image
-                    .store(bytes.clone(), self.storage.cache.clone(), &filename)
-                    .await?;
-                Ok(bytes)
+                self.render(image, variant).await
             }
         }
     }
 
+    async fn render(&self, image: &ImagioImage, variant: Variant) -> Result<Bytes, ImagioError> {
+        let filename = image.filename(&variant);
+        let original = image.filename(&Variant::Original);
+        let buf = self.storage.store.read(&original).await?;
+
+        let spec = variant.spec().cloned().unwrap_or_default();
+        let format = variant.format(image);
+        // Decoding and encoding take long enough to stall the runtime
+        let bytes = tokio::task::spawn_blocking(move || {
+            let img = ImageReader::new(std::io::Cursor::new(buf.to_bytes()))
+                .with_guessed_format()?
+                .decode()?;
+            spec.apply(img, format)
+        })
+        .await
+        .map_err(|err| ImagioError::IoError(std::io::Error::other(err)))??;
+        // Write the variant image to the store
+        image
+            .store(bytes.clone(), self.storage.cache.clone(), &filename)
+            .await?;
+        Ok(bytes)
+    }
+
+    pub(crate) async fn original_length(&self, image: &ImagioImage) -> Result<u64, ImagioError> {
+        let filename = image.filename(&Variant::Original);
+        Ok(self.storage.store.stat(&filename).await?.content_length())
+    }
+
+    pub(crate) async fn original_stream(
+        &self,
+        image: &ImagioImage,
+        range: Range<u64>,
+    ) -> Result<ReaderStream<Compat<FuturesAsyncReader>>, ImagioError> {
+        let filename = image.filename(&Variant::Original);
+        let reader = self.storage.store.reader(&filename).await?;
+        let reader = reader.into_futures_async_read(range).await?;
+        Ok(ReaderStream::new(reader.compat()))
+    }
+
     pub(crate) async fn variant(
         &self,
         image: &ImagioImage,
@@ -152,6 +307,170 @@ impl ImagioState {
     }
 }
 
-pub fn generate() -> Result<(), ImagioError> {
+enum Generated {
+    Rendered,
+    Skipped,
+}
+
+pub async fn generate(
+    state: Arc<ImagioState>,
+    categories: &[String],
+    variants: &[String],
+    concurrency: usize,
+    skip_cached: bool,
+) -> Result<(), ImagioError> {
+    let variants = match variants {
+        [] => state.variants.all(),
+        names => names
+            .iter()
+            .map(|name| {
+                state
+                    .variants
+                    .resolve(name)
+                    .map_err(|_| ImagioError::InvalidVariant(name.clone()))
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+    };
+    let images = state.list_all(categories).await?;
+    tracing::info!(
+        "Rendering {} variants for {} images",
+        variants.len(),
+        images.len()
+    );
+
+    let started = Instant::now();
+    let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+    let mut tasks = JoinSet::new();
+    let mut settings = HashMap::new();
+    for image in images {
+        let key = (image.account_id, image.category.clone());
+        if !settings.contains_key(&key) {
+            let category = state.category(image.account_id, &image.category).await?;
+            settings.insert(key.clone(), category);
+        }
+        let category = &settings[&key];
+        // Every format the variant may be served as: each negotiated one and
+        // the fallback for clients accepting none of them
+        let mut renders: Vec<Variant> = Vec::new();
+        for variant in variants.iter().filter(|v| category.allows(v)) {
+            let negotiated = match variant.negotiable() {
+                true => state.negotiate.clone(),
+                false => Vec::new(),
+            };
+            let fallback = match category.default_format {
+                Some(format) => variant.clone().with_format(format),
+                None => variant.clone(),
+            };
+            for render in negotiated
+                .into_iter()
+                .map(|format| variant.clone().with_format(format))
+                .chain(std::iter::once(fallback))
+            {
+                let filename = image.filename(&render);
+                if !renders.iter().any(|r| image.filename(r) == filename) {
+                    renders.push(render);
+                }
+            }
+        }
+        for variant in renders {
+            let permit = semaphore.clone().acquire_owned().await.unwrap();
+            let state = state.clone();
+            let image = image.clone();
+            tasks.spawn(async move {
+                let _permit = permit;
+                let filename = image.filename(&variant);
+                if skip_cached && state.storage.cache.is_exist(&filename).await? {
+                    return Ok(Generated::Skipped);
+                }
+                state.render(&image, variant).await.map_err(|err| {
+                    tracing::warn!("Failed to render {}: {}", filename, err);
+                    err
+                })?;
+                Ok::<_, ImagioError>(Generated::Rendered)
+            });
+        }
+    }
+
+    let (mut rendered, mut skipped, mut failed) = (0, 0, 0);
+    while let Some(result) = tasks.join_next().await {
+        match result {
+            Ok(Ok(Generated::Rendered)) => rendered += 1,
+            Ok(Ok(Generated::Skipped)) => skipped += 1,
+            Ok(Err(_)) => failed += 1,
+            Err(err) => {
+                tracing::warn!("Render task aborted: {}", err);
+                failed += 1;
+            }
+        }
+    }
+    tracing::info!(
+        "Finished in {:.2?}: {} rendered, {} skipped, {} failed",
+        started.elapsed(),
+        rendered,
+        skipped,
+        failed
+    );
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PREFERRED: [OutputFormat; 2] = [OutputFormat::Avif, OutputFormat::Webp];
+
+    fn preset(spec: &str) -> Variant {
+        Variant::Preset("thumb".to_string(), spec.parse().unwrap())
+    }
+
+    fn format(variant: &Variant) -> Option<OutputFormat> {
+        variant.spec().and_then(|spec| spec.format)
+    }
+
+    #[test]
+    fn negotiates_the_first_preferred_format() {
+        let variant = preset("w=100");
+        assert_eq!(
+            format(
+                &variant
+                    .clone()
+                    .negotiate("image/webp,image/avif", &PREFERRED)
+            ),
+            Some(OutputFormat::Avif)
+        );
+        assert_eq!(
+            format(&variant.clone().negotiate("image/webp,image/*", &PREFERRED)),
+            Some(OutputFormat::Webp)
+        );
+        assert_eq!(
+            format(
+                &variant
+                    .clone()
+                    .negotiate("image/avif;q=0,image/webp", &PREFERRED)
+            ),
+            Some(OutputFormat::Webp)
+        );
+        // Wildcards alone keep the original format
+        assert_eq!(format(&variant.clone().negotiate("*/*", &PREFERRED)), None);
+        assert_eq!(format(&variant.negotiate("image/webp", &[])), None);
+    }
+
+    #[test]
+    fn keeps_fixed_formats() {
+        let variant = preset("w=100,f=png").negotiate("image/avif", &PREFERRED);
+        assert_eq!(format(&variant), Some(OutputFormat::Png));
+        let variant = Variant::Original.negotiate("image/avif", &PREFERRED);
+        assert!(variant.spec().is_none());
+    }
+
+    #[test]
+    fn normalizes_negotiated_specs() {
+        // A lossless format drops the quality, like a spec that asked for it
+        let variant = preset("w=100,q=70").with_format(OutputFormat::Webp);
+        assert_eq!(variant.spec(), Some(&"w=100,f=webp".parse().unwrap()));
+        let variant = Variant::Custom("w=100,q=70".parse().unwrap()).with_format(OutputFormat::Png);
+        assert_eq!(variant.spec(), Some(&"w=100,f=png".parse().unwrap()));
+        let variant = preset("w=100,q=70").with_format(OutputFormat::Avif);
+        assert_eq!(variant.spec(), Some(&"w=100,f=avif,q=70".parse().unwrap()));
+    }
+}